@@ -0,0 +1,71 @@
+//! Command line client of the joycombinerd control socket.
+//!
+//! Usage: `joycombinerctl [-s|--socket PATH] <command...>`, where the command is one of
+//! `version`, `uptime`, `list`, `pair <token> <token>`, `lone <token>`, `horizontal <token>`,
+//! `unpair <group>`, `forget <group>`, `keymap <group> <name>`, `rumble <group> <routing>` and
+//! `reload`.
+
+use std::{
+    io::{Read, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use anyhow::{Context, Result as Anyhow};
+
+const DEFAULT_SOCKET_PATH: &str = "/run/joycombinerd.sock";
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Send the request and print the response. Return whether the daemon accepted the request.
+fn run() -> Anyhow<bool> {
+    let mut socket = PathBuf::from(DEFAULT_SOCKET_PATH);
+    let mut words = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-s" | "--socket" => {
+                socket = args
+                    .next()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| anyhow::anyhow!("{arg} requires a path"))?;
+            }
+            _ => words.push(arg),
+        }
+    }
+    if words.is_empty() {
+        Err(anyhow::anyhow!(
+            "Usage: joycombinerctl [-s|--socket PATH] <command...>"
+        ))?;
+    }
+
+    let mut stream = UnixStream::connect(&socket)
+        .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    stream.write_all(format!("{}\n", words.join(" ")).as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    match response.strip_prefix("ok\n") {
+        Some(body) => {
+            print!("{body}");
+            Ok(true)
+        }
+        None => {
+            eprint!("{response}");
+            Ok(false)
+        }
+    }
+}
@@ -0,0 +1,1147 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result as Anyhow};
+use serde::Deserialize;
+
+use crate::controller_manager::{
+    key_map::{self, Code, Rule, RuleKeyMap},
+    Button, DeadzoneShape, DeviceMatch, GestureAction, KeyMap, Model, ModelRegistry, ResponseCurve,
+    Role, RumbleRouting, StickSide,
+};
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/joycombinerd/config.toml";
+
+/// The built-in controller models, tried after the ones in the configuration.
+const BUILTIN_MODELS: &str = include_str!("controller_manager/models.toml");
+
+/// Daemon configuration, read from a TOML file at startup.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The udev tag the udev rules put on the controllers to be managed.
+    pub udev_tag: String,
+    /// Where sysfs is mounted, to drive the player LEDs.
+    pub sysfs_root: PathBuf,
+    /// The Unix socket `joycombinerctl` talks to.
+    pub control_socket: PathBuf,
+    pub virtual_device: VirtualDeviceConfig,
+    pub axis: AxisConfig,
+    pub key_maps: KeyMapsConfig,
+    /// User-defined key maps, referred by their names in `key_maps`.
+    pub custom_key_maps: HashMap<String, CustomKeyMapConfig>,
+    pub unpair_gesture: UnpairGestureConfig,
+    pub pairing: PairingConfig,
+    pub reconnect: ReconnectConfig,
+    pub rumble: RumbleConfig,
+    pub feedback: FeedbackConfig,
+    /// Stick processing, the first settings matching a stick applying to it. The sticks matching
+    /// none report their values unchanged.
+    pub sticks: Vec<StickConfig>,
+    /// Controller models, tried before the built-in ones. A model with the id of a built-in model
+    /// replaces it.
+    pub models: Vec<ModelConfig>,
+}
+
+/// Identity of the virtual controllers created by the daemon.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VirtualDeviceConfig {
+    pub name: String,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+}
+
+/// Parameters of the absolute axes of the virtual controllers. The axes take the parameters of the
+/// physical axes mapped to them, unless set here.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AxisConfig {
+    pub min: Option<i32>,
+    pub max: Option<i32>,
+    pub fuzz: Option<i32>,
+    pub flat: Option<i32>,
+    pub resolution: Option<i32>,
+}
+
+/// Names of the key maps used for each pairing mode.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyMapsConfig {
+    pub combined: String,
+    pub lone: String,
+    pub horizontal_left: String,
+    pub horizontal_right: String,
+}
+
+/// How the controllers are paired.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PairingConfig {
+    /// How long a controller waits for a partner, in milliseconds. 0 waits forever.
+    pub timeout_ms: u64,
+    pub on_timeout: PairingTimeoutAction,
+    pub matching: PairingMatching,
+    /// Remember the groups formed and form them again when their controllers show up.
+    pub remember: bool,
+    /// Where the remembered groups are kept.
+    pub state_file: PathBuf,
+}
+
+/// Which waiting controller of the other side a controller entering waiting is combined with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PairingMatching {
+    /// The one waiting for the longest time.
+    Arrival,
+    /// The one starting waiting the most recently.
+    Proximity,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PairingTimeoutAction {
+    /// Use the controller alone.
+    Lone,
+    /// Send the controller back to pairing.
+    Reset,
+}
+
+/// How combined groups survive brief disconnections of their controllers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectConfig {
+    /// How long a group keeps its virtual controller for a disconnected controller to come back,
+    /// in milliseconds. 0 dissolves the group at once.
+    pub grace_ms: u64,
+}
+
+/// Where the force feedback effects of the virtual controllers go.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RumbleConfig {
+    /// The routing of the groups formed, until changed from the control socket.
+    pub routing: RumbleRouting,
+    /// Scale of the strength of every effect, 1.0 leaving it unchanged.
+    pub intensity: f64,
+}
+
+/// Processing of the values of a stick before the key map: calibration, deadzones and response
+/// curve. Tilts are fractions of the full tilt, between 0 and 1.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StickConfig {
+    /// The `uniq` of the controllers, usually their MAC address. Every controller if not set.
+    pub uniq: Option<String>,
+    /// Both sticks if not set.
+    pub stick: Option<StickSide>,
+    /// Raw values of the X and Y axes at rest. The middle of the axis ranges if not set.
+    pub center: Option<[i32; 2]>,
+    /// Raw values of the X and Y axes at full tilt, on each side. The ends of the axis ranges if
+    /// not set.
+    pub min: Option<[i32; 2]>,
+    pub max: Option<[i32; 2]>,
+    /// Tilt ignored around the center.
+    pub deadzone: f64,
+    pub deadzone_shape: DeadzoneShape,
+    /// Tilt reported as soon as the stick leaves the deadzone, to make up for the deadzone of a
+    /// game.
+    pub anti_deadzone: f64,
+    /// Tilt near the edge reported as full tilt.
+    pub outer_deadzone: f64,
+    pub curve: ResponseCurve,
+    /// The `[input, output]` points of the custom curve, by increasing input. The curve goes from
+    /// `[0, 0]` to `[1, 1]` unless the points give other ends.
+    pub curve_points: Vec<[f64; 2]>,
+}
+
+/// Rumble pulses and LED patterns signaling the pairing transitions on the controllers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeedbackConfig {
+    /// Without feedback the controllers never rumble, and their LEDs keep showing the pairing
+    /// state: blinking while waiting for a partner or back to pairing, the player number in a
+    /// group.
+    pub enabled: bool,
+    /// A controller starts waiting for a partner.
+    pub waiting: FeedbackPattern,
+    /// A controller is used alone, held vertically.
+    pub lone: FeedbackPattern,
+    /// A controller is used alone, held horizontally.
+    pub horizontal: FeedbackPattern,
+    /// Two controllers are combined.
+    pub combined: FeedbackPattern,
+    /// A controller enters pairing: it connects, its group is dissolved, or it waited for a
+    /// partner for too long.
+    pub reset: FeedbackPattern,
+    /// A pairing gesture fails.
+    pub error: FeedbackPattern,
+}
+
+/// The feedback given on a pairing transition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeedbackPattern {
+    /// Number of rumble pulses, 0 for none.
+    pub pulses: u16,
+    /// Length of each pulse and of the pause between two pulses, in milliseconds.
+    pub pulse_ms: u16,
+    pub leds: LedFeedback,
+}
+
+/// What the player LEDs show after a pairing transition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LedFeedback {
+    /// Leave the LEDs as they are.
+    Keep,
+    Blink,
+    BlinkFast,
+    /// The player number of the group formed. Only for the transitions forming a group.
+    Player,
+}
+
+/// The pairing transitions given feedback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedbackEvent {
+    Waiting,
+    Lone,
+    Horizontal,
+    Combined,
+    Reset,
+    Error,
+}
+
+/// The chord held on every controller of a group to dissolve it and send the controllers back to
+/// pairing. Buttons a controller does not have are not required on it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnpairGestureConfig {
+    pub enabled: bool,
+    pub buttons: Vec<Button>,
+    /// How long the chord must be held, in milliseconds.
+    pub hold_ms: u64,
+}
+
+/// A key map defined by a table of rules.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomKeyMapConfig {
+    /// Forward the keys and axes matching no rule unchanged.
+    #[serde(default = "default_passthrough")]
+    pub passthrough: bool,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+/// A remapping rule. The kind of the rule depends on whether `from` and `to` are keys or axes:
+///
+/// - key to key: no extra parameter.
+/// - key to axis: `value` is required, the axis is set to it while the key is pressed.
+/// - axis to key: `threshold` is required, the key is pressed when the axis goes beyond it.
+/// - axis to axis: the axis is inverted if `invert` is set.
+///
+/// A rule with `controller` only applies to the physical device with that id in the group.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub from: String,
+    pub to: String,
+    pub controller: Option<usize>,
+    pub value: Option<i32>,
+    pub threshold: Option<i32>,
+    #[serde(default)]
+    pub invert: bool,
+}
+
+/// A controller model, any evdev device matching all of `vendor`, `product`, `name` (a part of the
+/// device name) and `uniq` given.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    pub id: String,
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    pub name: Option<String>,
+    pub uniq: Option<String>,
+    pub role: Role,
+    /// The pairing buttons by the key codes they report, e.g. `{ BTN_TL = "l" }`.
+    pub buttons: HashMap<String, Button>,
+    pub gestures: Vec<GestureConfig>,
+    /// Motion axes inverted to orient the IMU like a left joycon held vertically.
+    #[serde(default)]
+    pub invert_motion_axes: Vec<String>,
+}
+
+/// A pairing gesture, performed when exactly `buttons` are held.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GestureConfig {
+    pub buttons: Vec<Button>,
+    pub action: GestureAction,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModelsFile {
+    models: Vec<ModelConfig>,
+}
+
+fn default_passthrough() -> bool {
+    true
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            udev_tag: "joycombinerd".to_string(),
+            sysfs_root: PathBuf::from("/sys"),
+            control_socket: PathBuf::from("/run/joycombinerd.sock"),
+            virtual_device: VirtualDeviceConfig::default(),
+            axis: AxisConfig::default(),
+            key_maps: KeyMapsConfig::default(),
+            custom_key_maps: HashMap::new(),
+            unpair_gesture: UnpairGestureConfig::default(),
+            pairing: PairingConfig::default(),
+            reconnect: ReconnectConfig::default(),
+            rumble: RumbleConfig::default(),
+            feedback: FeedbackConfig::default(),
+            sticks: vec![],
+            models: vec![],
+        }
+    }
+}
+
+impl Default for VirtualDeviceConfig {
+    fn default() -> Self {
+        // HACK: 0x2008 is an illegal product id for nintendo joycons, preventing re-registering
+        // the virtual controllers.
+        Self {
+            name: "Nintendo Switch Combined Joycons".to_string(),
+            vendor: 0x059e,
+            product: 0x2008,
+            version: 0x0000,
+        }
+    }
+}
+
+impl Default for KeyMapsConfig {
+    fn default() -> Self {
+        Self {
+            combined: key_map::COMBINED.to_string(),
+            lone: key_map::ID.to_string(),
+            horizontal_left: key_map::HORIZONTAL_LEFT.to_string(),
+            horizontal_right: key_map::HORIZONTAL_RIGHT.to_string(),
+        }
+    }
+}
+
+impl Default for PairingConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 10000,
+            on_timeout: PairingTimeoutAction::Reset,
+            matching: PairingMatching::Arrival,
+            remember: true,
+            state_file: PathBuf::from("/var/lib/joycombinerd/pairings.toml"),
+        }
+    }
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self { grace_ms: 5000 }
+    }
+}
+
+impl Default for RumbleConfig {
+    fn default() -> Self {
+        Self {
+            routing: RumbleRouting::Mirror,
+            intensity: 1.0,
+        }
+    }
+}
+
+impl Default for StickConfig {
+    fn default() -> Self {
+        Self {
+            uniq: None,
+            stick: None,
+            center: None,
+            min: None,
+            max: None,
+            deadzone: 0.0,
+            deadzone_shape: DeadzoneShape::Radial,
+            anti_deadzone: 0.0,
+            outer_deadzone: 0.0,
+            curve: ResponseCurve::Linear,
+            curve_points: vec![],
+        }
+    }
+}
+
+impl Default for FeedbackConfig {
+    fn default() -> Self {
+        let pattern = |pulses, pulse_ms, leds| FeedbackPattern {
+            pulses,
+            pulse_ms,
+            leds,
+        };
+        Self {
+            enabled: true,
+            waiting: pattern(1, 60, LedFeedback::BlinkFast),
+            lone: pattern(1, 150, LedFeedback::Player),
+            horizontal: pattern(2, 100, LedFeedback::Player),
+            combined: pattern(1, 200, LedFeedback::Player),
+            reset: pattern(1, 200, LedFeedback::Blink),
+            error: pattern(3, 80, LedFeedback::Keep),
+        }
+    }
+}
+
+impl Default for UnpairGestureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            buttons: vec![Button::Sl, Button::Sr],
+            hold_ms: 2000,
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from `path`. A missing file at the default path falls back to the
+    /// default configuration, while a missing file at any other path is an error.
+    pub fn load(path: &Path) -> Anyhow<Self> {
+        if !path.exists() && path == Path::new(DEFAULT_CONFIG_PATH) {
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the config file {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Anyhow<Self> {
+        let config: Self = toml::from_str(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Anyhow<()> {
+        if self.udev_tag.is_empty() {
+            Err(anyhow::anyhow!("`udev_tag` must not be empty"))?;
+        }
+
+        self.virtual_device.validate()?;
+        self.axis.validate()?;
+        self.unpair_gesture.validate()?;
+        self.rumble.validate()?;
+        self.feedback.validate()?;
+        for (i, stick) in self.sticks.iter().enumerate() {
+            stick
+                .validate()
+                .with_context(|| format!("Invalid stick settings #{i}"))?;
+        }
+        self.model_registry()?;
+        for (name, custom_key_map) in &self.custom_key_maps {
+            if key_map::from_name(name).is_ok() {
+                Err(anyhow::anyhow!(
+                    "`custom_key_maps.{name}` conflicts with the built-in key map {name}"
+                ))?;
+            }
+            custom_key_map
+                .build()
+                .with_context(|| format!("Invalid key map `custom_key_maps.{name}`"))?;
+        }
+        for (mode, name) in [
+            ("combined", &self.key_maps.combined),
+            ("lone", &self.key_maps.lone),
+            ("horizontal_left", &self.key_maps.horizontal_left),
+            ("horizontal_right", &self.key_maps.horizontal_right),
+        ] {
+            self.key_map(name)
+                .with_context(|| format!("Invalid key map for `key_maps.{mode}`"))?;
+        }
+
+        Ok(())
+    }
+
+    /// The settings of a stick of the controller with `uniq`.
+    pub fn stick(&self, uniq: Option<&str>, side: StickSide) -> Option<&StickConfig> {
+        self.sticks.iter().find(|stick| {
+            stick.stick.is_none_or(|stick| stick == side)
+                && stick.uniq.as_deref().is_none_or(|stick_uniq| {
+                    uniq.is_some_and(|uniq| uniq.eq_ignore_ascii_case(stick_uniq))
+                })
+        })
+    }
+
+    /// Whether the key map named `name` is defined the same way in both configurations.
+    pub fn same_key_map(&self, other: &Config, name: &str) -> bool {
+        self.custom_key_maps.get(name) == other.custom_key_maps.get(name)
+    }
+
+    /// Build a key map by its name, either a custom key map or a built-in one.
+    pub fn key_map(&self, name: &str) -> Anyhow<Box<dyn KeyMap>> {
+        match self.custom_key_maps.get(name) {
+            Some(custom_key_map) => Ok(Box::new(custom_key_map.build()?)),
+            None => key_map::from_name(name),
+        }
+    }
+
+    /// Build the model registry from the models of the configuration and the built-in ones.
+    pub fn model_registry(&self) -> Anyhow<ModelRegistry> {
+        let builtin: ModelsFile =
+            toml::from_str(BUILTIN_MODELS).with_context(|| "Invalid built-in models")?;
+        let mut ids = vec![];
+        for model in &self.models {
+            if ids.contains(&&model.id) {
+                Err(anyhow::anyhow!("Duplicate model `{}`", model.id))?;
+            }
+            ids.push(&model.id);
+        }
+
+        let models = self
+            .models
+            .iter()
+            .chain(
+                builtin
+                    .models
+                    .iter()
+                    .filter(|model| !ids.contains(&&model.id)),
+            )
+            .map(|model| {
+                model
+                    .build()
+                    .with_context(|| format!("Invalid model `{}`", model.id))
+            })
+            .collect::<Anyhow<_>>()?;
+
+        Ok(ModelRegistry::new(models))
+    }
+}
+
+impl VirtualDeviceConfig {
+    fn validate(&self) -> Anyhow<()> {
+        if self.name.is_empty() {
+            Err(anyhow::anyhow!("`virtual_device.name` must not be empty"))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AxisConfig {
+    fn validate(&self) -> Anyhow<()> {
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min >= max {
+                Err(anyhow::anyhow!(
+                    "`axis.min` ({min}) must be less than `axis.max` ({max})"
+                ))?;
+            }
+        }
+        if [self.fuzz, self.flat, self.resolution]
+            .into_iter()
+            .flatten()
+            .any(|value| value < 0)
+        {
+            Err(anyhow::anyhow!(
+                "`axis.fuzz`, `axis.flat` and `axis.resolution` must not be negative"
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl UnpairGestureConfig {
+    fn validate(&self) -> Anyhow<()> {
+        if self.enabled && self.buttons.is_empty() {
+            Err(anyhow::anyhow!(
+                "`unpair_gesture.buttons` must not be empty when the gesture is enabled"
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl RumbleConfig {
+    fn validate(&self) -> Anyhow<()> {
+        if !self.intensity.is_finite() || self.intensity < 0.0 {
+            Err(anyhow::anyhow!(
+                "`rumble.intensity` ({}) must be a non-negative number",
+                self.intensity
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl StickConfig {
+    fn validate(&self) -> Anyhow<()> {
+        for (name, tilt) in [
+            ("deadzone", self.deadzone),
+            ("anti_deadzone", self.anti_deadzone),
+            ("outer_deadzone", self.outer_deadzone),
+        ] {
+            if !(0.0..1.0).contains(&tilt) {
+                Err(anyhow::anyhow!("`{name}` ({tilt}) must be in [0, 1)"))?;
+            }
+        }
+        if self.deadzone + self.outer_deadzone >= 1.0 {
+            Err(anyhow::anyhow!(
+                "`deadzone` and `outer_deadzone` must leave some tilt between them"
+            ))?;
+        }
+        for i in 0..2 {
+            let center = self.center.map(|center| center[i]);
+            let bounds = [
+                (self.min.map(|min| min[i]), center),
+                (center, self.max.map(|max| max[i])),
+                (self.min.map(|min| min[i]), self.max.map(|max| max[i])),
+            ];
+            if bounds
+                .into_iter()
+                .any(|bound| matches!(bound, (Some(low), Some(high)) if low >= high))
+            {
+                Err(anyhow::anyhow!(
+                    "`min`, `center` and `max` must increase on each axis"
+                ))?;
+            }
+        }
+        match (self.curve, self.curve_points.is_empty()) {
+            (ResponseCurve::Custom, true) => Err(anyhow::anyhow!(
+                "`curve_points` is required for a custom curve"
+            ))?,
+            (ResponseCurve::Custom, false) => {}
+            (curve, false) => Err(anyhow::anyhow!(
+                "`curve_points` is only for a custom curve, not {curve:?}"
+            ))?,
+            (_, true) => {}
+        }
+        if self
+            .curve_points
+            .iter()
+            .flatten()
+            .any(|value| !(0.0..=1.0).contains(value))
+        {
+            Err(anyhow::anyhow!("`curve_points` must be in [0, 1]"))?;
+        }
+        if self
+            .curve_points
+            .windows(2)
+            .any(|points| points[0][0] >= points[1][0])
+        {
+            Err(anyhow::anyhow!(
+                "`curve_points` must be sorted by increasing input"
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FeedbackConfig {
+    fn validate(&self) -> Anyhow<()> {
+        for (event, name) in [
+            (FeedbackEvent::Waiting, "waiting"),
+            (FeedbackEvent::Lone, "lone"),
+            (FeedbackEvent::Horizontal, "horizontal"),
+            (FeedbackEvent::Combined, "combined"),
+            (FeedbackEvent::Reset, "reset"),
+            (FeedbackEvent::Error, "error"),
+        ] {
+            let pattern = self.get(event);
+            if pattern.pulses > 0 && pattern.pulse_ms == 0 {
+                Err(anyhow::anyhow!(
+                    "`feedback.{name}.pulse_ms` must not be 0 with pulses"
+                ))?;
+            }
+            if pattern.leds == LedFeedback::Player && !event.forms_group() {
+                Err(anyhow::anyhow!(
+                    "`feedback.{name}.leds` cannot show a player number, no group is formed"
+                ))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, event: FeedbackEvent) -> FeedbackPattern {
+        match event {
+            FeedbackEvent::Waiting => self.waiting,
+            FeedbackEvent::Lone => self.lone,
+            FeedbackEvent::Horizontal => self.horizontal,
+            FeedbackEvent::Combined => self.combined,
+            FeedbackEvent::Reset => self.reset,
+            FeedbackEvent::Error => self.error,
+        }
+    }
+
+    /// The feedback given on a pairing transition. Without feedback there is no pulse, and the
+    /// LEDs follow the default patterns.
+    pub fn pattern(&self, event: FeedbackEvent) -> FeedbackPattern {
+        if self.enabled {
+            self.get(event)
+        } else {
+            FeedbackPattern {
+                pulses: 0,
+                ..Self::default().get(event)
+            }
+        }
+    }
+}
+
+impl FeedbackEvent {
+    fn forms_group(self) -> bool {
+        matches!(self, Self::Lone | Self::Horizontal | Self::Combined)
+    }
+}
+
+impl CustomKeyMapConfig {
+    pub fn build(&self) -> Anyhow<RuleKeyMap> {
+        let rules = self
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                rule.build()
+                    .map(|built| (rule.controller, built))
+                    .with_context(|| format!("Invalid rule #{i}"))
+            })
+            .collect::<Anyhow<_>>()?;
+
+        Ok(RuleKeyMap::new(rules, self.passthrough))
+    }
+}
+
+impl ModelConfig {
+    fn build(&self) -> Anyhow<Model> {
+        if self.id.is_empty() {
+            Err(anyhow::anyhow!("`id` must not be empty"))?;
+        }
+        let device_match = DeviceMatch {
+            vendor: self.vendor,
+            product: self.product,
+            name: self.name.clone(),
+            uniq: self.uniq.clone(),
+        };
+        if device_match.vendor.is_none()
+            && device_match.product.is_none()
+            && device_match.name.is_none()
+            && device_match.uniq.is_none()
+        {
+            Err(anyhow::anyhow!(
+                "At least one of `vendor`, `product`, `name` and `uniq` is required"
+            ))?;
+        }
+        let buttons = self
+            .buttons
+            .iter()
+            .map(|(code, &button)| match code.parse()? {
+                Code::Key(key) => Ok((key, button)),
+                Code::Axis(_) => Err(anyhow::anyhow!("{code} is not a key")),
+            })
+            .collect::<Anyhow<HashMap<_, _>>>()?;
+
+        let mut gestures = vec![];
+        for gesture in &self.gestures {
+            if gesture.buttons.is_empty() {
+                Err(anyhow::anyhow!("A gesture must have at least one button"))?;
+            }
+            if let Some(button) = gesture
+                .buttons
+                .iter()
+                .find(|button| !buttons.values().any(|b| b == *button))
+            {
+                Err(anyhow::anyhow!(
+                    "The gesture button {button:?} is not in `buttons`"
+                ))?;
+            }
+            if self.role == Role::Single && gesture.action != GestureAction::Lone {
+                Err(anyhow::anyhow!(
+                    "A single controller only has lone gestures, not {:?}",
+                    gesture.action
+                ))?;
+            }
+            gestures.push((gesture.buttons.clone(), gesture.action));
+        }
+
+        let inverted_motion_axes = self
+            .invert_motion_axes
+            .iter()
+            .map(|axis| match axis.parse()? {
+                Code::Axis(axis) => Ok(axis),
+                Code::Key(_) => Err(anyhow::anyhow!("{axis} is not an axis")),
+            })
+            .collect::<Anyhow<_>>()?;
+
+        Ok(Model {
+            id: self.id.clone(),
+            device_match,
+            role: self.role,
+            buttons,
+            gestures,
+            inverted_motion_axes,
+        })
+    }
+}
+
+impl RuleConfig {
+    fn build(&self) -> Anyhow<Rule> {
+        let from: Code = self.from.parse()?;
+        let to: Code = self.to.parse()?;
+
+        let rule = match (from, to) {
+            (Code::Key(from), Code::Key(to)) => Rule::KeyToKey { from, to },
+            (Code::Key(from), Code::Axis(to)) => Rule::KeyToAxis {
+                from,
+                to,
+                value: self.value.ok_or_else(|| {
+                    anyhow::anyhow!("`value` is required to map a key to an axis")
+                })?,
+            },
+            (Code::Axis(from), Code::Key(to)) => Rule::AxisToKey {
+                from,
+                to,
+                threshold: self
+                    .threshold
+                    .filter(|&threshold| threshold != 0)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "A non-zero `threshold` is required to map an axis to a key"
+                        )
+                    })?,
+            },
+            (Code::Axis(from), Code::Axis(to)) => Rule::AxisToAxis {
+                from,
+                to,
+                invert: self.invert,
+            },
+        };
+
+        if self.value.is_some() && !matches!(rule, Rule::KeyToAxis { .. }) {
+            Err(anyhow::anyhow!("`value` only applies to key to axis rules"))?;
+        }
+        if self.threshold.is_some() && !matches!(rule, Rule::AxisToKey { .. }) {
+            Err(anyhow::anyhow!(
+                "`threshold` only applies to axis to key rules"
+            ))?;
+        }
+        if self.invert && !matches!(rule, Rule::AxisToAxis { .. }) {
+            Err(anyhow::anyhow!(
+                "`invert` only applies to axis to axis rules"
+            ))?;
+        }
+
+        Ok(rule)
+    }
+}
+
+/// Get the config path from the command line arguments.
+pub fn config_path_from_args(mut args: impl Iterator<Item = String>) -> Anyhow<PathBuf> {
+    let mut path = PathBuf::from(DEFAULT_CONFIG_PATH);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                path = args
+                    .next()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for {arg}"))?;
+            }
+            _ => Err(anyhow::anyhow!("Unknown argument: {arg}"))?,
+        }
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_is_default() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.udev_tag, "joycombinerd");
+        assert_eq!(
+            config.control_socket,
+            PathBuf::from("/run/joycombinerd.sock")
+        );
+        assert_eq!(config.virtual_device.product, 0x2008);
+        assert_eq!(config.axis.max, None);
+        assert_eq!(config.key_maps.horizontal_left, key_map::HORIZONTAL_LEFT);
+    }
+
+    #[test]
+    fn partial_config() {
+        let config = Config::parse(
+            r#"
+            [virtual_device]
+            name = "Combined"
+            product = 0x2010
+
+            [axis]
+            flat = 1000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.virtual_device.name, "Combined");
+        assert_eq!(config.virtual_device.vendor, 0x059e);
+        assert_eq!(config.virtual_device.product, 0x2010);
+        assert_eq!(config.axis.flat, Some(1000));
+        assert_eq!(config.axis.fuzz, None);
+    }
+
+    #[test]
+    fn unpair_gesture() {
+        let config = Config::parse("[unpair_gesture]\nbuttons = [\"l\", \"r\"]").unwrap();
+        assert!(config.unpair_gesture.enabled);
+        assert_eq!(config.unpair_gesture.buttons, [Button::L, Button::R]);
+        assert_eq!(config.unpair_gesture.hold_ms, 2000);
+
+        let config = Config::parse("[unpair_gesture]\nenabled = false\nbuttons = []").unwrap();
+        assert!(!config.unpair_gesture.enabled);
+    }
+
+    #[test]
+    fn pairing() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.pairing.on_timeout, PairingTimeoutAction::Reset);
+        assert!(config.pairing.remember);
+        assert_eq!(config.pairing.matching, PairingMatching::Arrival);
+        assert_eq!(config.reconnect.grace_ms, 5000);
+
+        let config = Config::parse("[pairing]\ntimeout_ms = 0\non_timeout = \"lone\"").unwrap();
+        assert_eq!(config.pairing.timeout_ms, 0);
+        assert_eq!(config.pairing.on_timeout, PairingTimeoutAction::Lone);
+        assert!(Config::parse("[pairing]\non_timeout = \"horizontal\"").is_err());
+    }
+
+    #[test]
+    fn rumble() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.rumble.routing, RumbleRouting::Mirror);
+        assert_eq!(config.rumble.intensity, 1.0);
+
+        let config = Config::parse("[rumble]\nrouting = \"split\"\nintensity = 0.5").unwrap();
+        assert_eq!(config.rumble.routing, RumbleRouting::Split);
+        assert_eq!(config.rumble.intensity, 0.5);
+        assert!(Config::parse("[rumble]\nrouting = \"both\"").is_err());
+        assert!(Config::parse("[rumble]\nintensity = -1.0").is_err());
+    }
+
+    #[test]
+    fn feedback() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.feedback.pattern(FeedbackEvent::Horizontal).pulses, 2);
+
+        let config = Config::parse(
+            r#"
+            [feedback]
+            enabled = false
+            error = { pulses = 5, pulse_ms = 50, leds = "blink-fast" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.feedback.error,
+            FeedbackPattern {
+                pulses: 5,
+                pulse_ms: 50,
+                leds: LedFeedback::BlinkFast
+            }
+        );
+        assert_eq!(
+            config.feedback.pattern(FeedbackEvent::Error),
+            FeedbackPattern {
+                pulses: 0,
+                pulse_ms: 80,
+                leds: LedFeedback::Keep
+            }
+        );
+        assert_eq!(
+            config.feedback.pattern(FeedbackEvent::Waiting).leds,
+            LedFeedback::BlinkFast
+        );
+
+        // A pattern is given whole.
+        assert!(Config::parse("[feedback]\nlone = { pulses = 1 }").is_err());
+        assert!(
+            Config::parse("[feedback]\nlone = { pulses = 1, pulse_ms = 0, leds = \"keep\" }")
+                .is_err()
+        );
+        assert!(Config::parse(
+            "[feedback]\nreset = { pulses = 0, pulse_ms = 0, leds = \"player\" }"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn sticks() {
+        let config = Config::parse(
+            r#"
+            [[sticks]]
+            uniq = "98:B6:E9:12:34:56"
+            stick = "left"
+            center = [1200, -800]
+            deadzone = 0.15
+            curve = "custom"
+            curve_points = [[0.5, 0.3]]
+
+            [[sticks]]
+            deadzone = 0.05
+            deadzone_shape = "axial"
+            "#,
+        )
+        .unwrap();
+        let drifting = config
+            .stick(Some("98:b6:e9:12:34:56"), StickSide::Left)
+            .unwrap();
+        assert_eq!(drifting.center, Some([1200, -800]));
+        assert_eq!(drifting.curve, ResponseCurve::Custom);
+        let other = config
+            .stick(Some("98:b6:e9:12:34:56"), StickSide::Right)
+            .unwrap();
+        assert_eq!(other.deadzone_shape, DeadzoneShape::Axial);
+        assert_eq!(config.stick(None, StickSide::Left), Some(other));
+        assert_eq!(Config::default().stick(None, StickSide::Left), None);
+
+        for invalid in [
+            "deadzone = 1.0",
+            "deadzone = 0.5\nouter_deadzone = 0.5",
+            "anti_deadzone = -0.1",
+            "center = [100, 0]\nmax = [100, 32767]",
+            "curve = \"custom\"",
+            "curve_points = [[0.5, 0.5]]",
+            "curve = \"custom\"\ncurve_points = [[0.5, 0.5], [0.4, 0.6]]",
+            "curve = \"custom\"\ncurve_points = [[0.5, 1.5]]",
+            "stick = \"middle\"",
+        ] {
+            assert!(Config::parse(&format!("[[sticks]]\n{invalid}")).is_err());
+        }
+    }
+
+    #[test]
+    fn example_config() {
+        Config::parse(include_str!("../config/config.toml")).unwrap();
+    }
+
+    #[test]
+    fn custom_key_map() {
+        let config = Config::parse(
+            r#"
+            [key_maps]
+            combined = "xbox"
+
+            [custom_key_maps.xbox]
+            rules = [
+                { from = "BTN_EAST", to = "BTN_SOUTH" },
+                { from = "BTN_SOUTH", to = "BTN_EAST" },
+                { from = "ABS_RY", to = "ABS_RY", invert = true, controller = 1 },
+            ]
+            "#,
+        )
+        .unwrap();
+        let key_map = config.key_map("xbox").unwrap();
+        assert_eq!(
+            key_map.map_key(0, evdev::EventType::KEY, evdev::Key::BTN_EAST.code(), 1),
+            Some((evdev::EventType::KEY, evdev::Key::BTN_SOUTH.code(), 1))
+        );
+    }
+
+    #[test]
+    fn invalid_custom_key_maps() {
+        let rule = |rule: &str| Config::parse(&format!("[custom_key_maps.test]\nrules = [{rule}]"));
+        assert!(rule(r#"{ from = "BTN_EAST", to = "BTN_NOPE" }"#).is_err());
+        assert!(rule(r#"{ from = "BTN_EAST", to = "ABS_HAT0X" }"#).is_err());
+        assert!(rule(r#"{ from = "ABS_Z", to = "BTN_TL2" }"#).is_err());
+        assert!(rule(r#"{ from = "ABS_Z", to = "BTN_TL2", threshold = 0 }"#).is_err());
+        assert!(rule(r#"{ from = "BTN_EAST", to = "BTN_WEST", invert = true }"#).is_err());
+        assert!(rule(r#"{ from = "ABS_Z", to = "BTN_TL2", threshold = 100 }"#).is_ok());
+        assert!(Config::parse("[custom_key_maps.id]").is_err());
+    }
+
+    #[test]
+    fn same_key_map() {
+        let xbox = |to: &str| {
+            Config::parse(&format!(
+                "[custom_key_maps.xbox]\nrules = [{{ from = \"BTN_EAST\", to = \"{to}\" }}]"
+            ))
+            .unwrap()
+        };
+        let config = xbox("BTN_SOUTH");
+        assert!(config.same_key_map(&xbox("BTN_SOUTH"), "xbox"));
+        assert!(!config.same_key_map(&xbox("BTN_NORTH"), "xbox"));
+        assert!(!config.same_key_map(&Config::default(), "xbox"));
+        assert!(config.same_key_map(&Config::default(), key_map::ID));
+    }
+
+    #[test]
+    fn invalid_models() {
+        let model = |buttons: &str, gestures: &str| {
+            Config::parse(&format!(
+                "[[models]]\nid = \"test\"\nvendor = 1\nproduct = 2\nrole = \"single\"\n\
+                 buttons = {buttons}\ngestures = {gestures}"
+            ))
+        };
+        assert!(model(
+            r#"{ BTN_TL = "l" }"#,
+            r#"[{ buttons = ["l"], action = "lone" }]"#
+        )
+        .is_ok());
+        assert!(model(
+            r#"{ ABS_X = "l" }"#,
+            r#"[{ buttons = ["l"], action = "lone" }]"#
+        )
+        .is_err());
+        assert!(model(
+            r#"{ BTN_TL = "l" }"#,
+            r#"[{ buttons = ["r"], action = "lone" }]"#
+        )
+        .is_err());
+        assert!(model(
+            r#"{ BTN_TL = "l" }"#,
+            r#"[{ buttons = [], action = "lone" }]"#
+        )
+        .is_err());
+        assert!(model(
+            r#"{ BTN_TL = "l" }"#,
+            r#"[{ buttons = ["l"], action = "waiting" }]"#
+        )
+        .is_err());
+        assert!(Config::parse(
+            "[[models]]\nid = \"any\"\nrole = \"single\"\nbuttons = {}\ngestures = []"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn invalid_configs() {
+        assert!(Config::parse("unknown = 1").is_err());
+        assert!(Config::parse("[axis]\nmin = 10\nmax = 10").is_err());
+        assert!(Config::parse("[axis]\nfuzz = -1").is_err());
+        assert!(Config::parse("[key_maps]\nlone = \"nope\"").is_err());
+        assert!(Config::parse("[unpair_gesture]\nbuttons = []").is_err());
+        assert!(Config::parse("[unpair_gesture]\nbuttons = [\"home\"]").is_err());
+    }
+
+    #[test]
+    fn args() {
+        let args = |args: &[&str]| config_path_from_args(args.iter().map(|s| s.to_string()));
+        assert_eq!(args(&[]).unwrap(), PathBuf::from(DEFAULT_CONFIG_PATH));
+        assert_eq!(
+            args(&["--config", "/tmp/a.toml"]).unwrap(),
+            PathBuf::from("/tmp/a.toml")
+        );
+        assert!(args(&["-c"]).is_err());
+        assert!(args(&["--foo"]).is_err());
+    }
+}
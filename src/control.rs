@@ -0,0 +1,248 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    os::unix::{fs::PermissionsExt, net::UnixListener, net::UnixStream},
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{Context, Result as Anyhow};
+
+use crate::{
+    controller_manager::{ControllerManager, ControllerMessage, RumbleRouting},
+    poll_manager::PollCallback,
+};
+
+/// The longest request line accepted.
+const MAX_REQUEST_LENGTH: usize = 4096;
+
+/// How long an accepted connection may take to send its request before it is closed.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A request sent to the control socket. Requests are single lines of space-separated words, and
+/// responses are `ok` followed by the result, or a single `error: <message>` line.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request {
+    Version,
+    Uptime,
+    /// List the waiting controllers and the combined groups.
+    List,
+    /// Combine two waiting controllers by their tokens.
+    Pair(usize, usize),
+    /// Use a waiting controller alone, held vertically.
+    Lone(usize),
+    /// Use a waiting controller alone, held horizontally.
+    Horizontal(usize),
+    /// Dissolve a combined group.
+    Unpair(usize),
+    /// Forget the remembered pairing of a combined group, keeping the group.
+    Forget(usize),
+    /// Reload the configuration file, as SIGHUP does.
+    Reload,
+    /// Switch the key map of a combined group.
+    KeyMap(usize, String),
+    /// Change where the rumble of a combined group goes.
+    Rumble(usize, RumbleRouting),
+}
+
+impl FromStr for Request {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let number = |word: &str| {
+            word.parse::<usize>()
+                .with_context(|| format!("Invalid number: {word}"))
+        };
+
+        match words.as_slice() {
+            ["version"] => Ok(Request::Version),
+            ["uptime"] => Ok(Request::Uptime),
+            ["list"] => Ok(Request::List),
+            ["reload"] => Ok(Request::Reload),
+            ["pair", left, right] => Ok(Request::Pair(number(left)?, number(right)?)),
+            ["lone", token] => Ok(Request::Lone(number(token)?)),
+            ["horizontal", token] => Ok(Request::Horizontal(number(token)?)),
+            ["forget", group] => Ok(Request::Forget(number(group)?)),
+            ["unpair", group] => Ok(Request::Unpair(number(group)?)),
+            ["keymap", group, name] => Ok(Request::KeyMap(number(group)?, name.to_string())),
+            ["rumble", group, routing] => Ok(Request::Rumble(number(group)?, routing.parse()?)),
+            _ => Err(anyhow::anyhow!("Invalid request: {}", s.trim())),
+        }
+    }
+}
+
+pub struct ControlServer;
+
+impl ControlServer {
+    /// Listen on the control socket, replacing any stale socket file. Only the owner can access
+    /// the socket.
+    pub fn bind(path: &Path) -> Anyhow<UnixListener> {
+        if path.exists() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove the stale socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind the control socket {}", path.display()))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+        Ok(listener)
+    }
+
+    pub fn callback(listener: UnixListener) -> ControlCallback {
+        ControlCallback::new(listener)
+    }
+
+    /// The callback reading the request of an accepted connection.
+    pub fn connection(stream: UnixStream) -> ConnectionCallback {
+        ConnectionCallback::new(stream)
+    }
+
+    /// Write the response of a request and close the connection.
+    pub fn respond(mut stream: UnixStream, response: Anyhow<String>) -> Anyhow<()> {
+        let response = match response {
+            Ok(body) if body.is_empty() => "ok\n".to_string(),
+            Ok(body) => format!("ok\n{}\n", body.trim_end()),
+            Err(e) => format!("error: {e:#}\n"),
+        };
+        stream
+            .write_all(response.as_bytes())
+            .with_context(|| "Failed to write the control response")
+    }
+}
+
+pub struct ControlCallback {
+    listener: UnixListener,
+}
+
+impl ControlCallback {
+    pub fn new(listener: UnixListener) -> Self {
+        Self { listener }
+    }
+}
+
+impl PollCallback<ControllerManager, Anyhow<ControllerMessage>> for ControlCallback {
+    /// Accept a connection. Its request is read once it arrives, so that a silent client does not
+    /// hold the event loop.
+    fn call(&mut self, _ctx: &mut ControllerManager) -> Anyhow<ControllerMessage> {
+        let (stream, _) = self
+            .listener
+            .accept()
+            .with_context(|| "Failed to accept a control connection")?;
+        stream.set_nonblocking(true)?;
+
+        Ok(ControllerMessage::ControlConnection(stream))
+    }
+}
+
+/// Reads the request line of a control connection as it arrives.
+pub struct ConnectionCallback {
+    /// Handed over with the request once it is read.
+    stream: Option<UnixStream>,
+    buffer: Vec<u8>,
+}
+
+impl ConnectionCallback {
+    pub fn new(stream: UnixStream) -> Self {
+        Self {
+            stream: Some(stream),
+            buffer: vec![],
+        }
+    }
+}
+
+impl PollCallback<ControllerManager, Anyhow<ControllerMessage>> for ConnectionCallback {
+    fn call(&mut self, _ctx: &mut ControllerManager) -> Anyhow<ControllerMessage> {
+        let mut stream = self
+            .stream
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("The control request is already read"))?;
+        match read_request(&mut stream, &mut self.buffer) {
+            Some(line) => Ok(ControllerMessage::ControlRequest(stream, line)),
+            None => {
+                self.stream = Some(stream);
+                Ok(ControllerMessage::Relay)
+            }
+        }
+    }
+}
+
+/// Read what the client sent. Return the request line once it is complete or the client
+/// stops sending, or `None` while more is expected.
+fn read_request(stream: &mut UnixStream, buffer: &mut Vec<u8>) -> Option<Anyhow<String>> {
+    let mut chunk = [0; 256];
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => {
+                buffer.extend_from_slice(&chunk[..n]);
+                if buffer.contains(&b'\n') {
+                    break;
+                }
+                if buffer.len() > MAX_REQUEST_LENGTH {
+                    return Some(Err(anyhow::anyhow!("The control request is too long")));
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                return Some(Err(
+                    anyhow::Error::new(e).context("Failed to read the control request")
+                ))
+            }
+        }
+    }
+
+    let line = buffer.split(|&b| b == b'\n').next().unwrap_or_default();
+    Some(String::from_utf8(line.to_vec()).with_context(|| "The control request is not valid UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_requests() {
+        assert_eq!("version\n".parse::<Request>().unwrap(), Request::Version);
+        assert_eq!("list".parse::<Request>().unwrap(), Request::List);
+        assert_eq!("pair 1 2".parse::<Request>().unwrap(), Request::Pair(1, 2));
+        assert_eq!(
+            " keymap 0  xbox ".parse::<Request>().unwrap(),
+            Request::KeyMap(0, "xbox".to_string())
+        );
+        assert_eq!(
+            "horizontal 3".parse::<Request>().unwrap(),
+            Request::Horizontal(3)
+        );
+        assert_eq!(
+            "rumble 1 split".parse::<Request>().unwrap(),
+            Request::Rumble(1, RumbleRouting::Split)
+        );
+        assert!("rumble 1 both".parse::<Request>().is_err());
+        assert!("pair 1".parse::<Request>().is_err());
+        assert!("unpair x".parse::<Request>().is_err());
+        assert!("".parse::<Request>().is_err());
+    }
+
+    #[test]
+    fn read_request_as_it_arrives() {
+        let (mut server, mut client) = UnixStream::pair().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let mut buffer = vec![];
+        assert!(read_request(&mut server, &mut buffer).is_none());
+
+        client.write_all(b"pair 1").unwrap();
+        assert!(read_request(&mut server, &mut buffer).is_none());
+        client.write_all(b" 2\nignored").unwrap();
+        let line = read_request(&mut server, &mut buffer).unwrap().unwrap();
+        assert_eq!(line.parse::<Request>().unwrap(), Request::Pair(1, 2));
+
+        // A client closing without a newline still sends its request.
+        let (mut server, mut client) = UnixStream::pair().unwrap();
+        server.set_nonblocking(true).unwrap();
+        client.write_all(b"list").unwrap();
+        drop(client);
+        let line = read_request(&mut server, &mut vec![]).unwrap().unwrap();
+        assert_eq!(line, "list");
+    }
+}
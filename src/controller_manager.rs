@@ -1,22 +1,48 @@
-use std::{cell::RefCell, collections::HashMap, path::PathBuf, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    os::{
+        fd::{AsRawFd, BorrowedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    path::PathBuf,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 use combined_controller_manager::CombinedControllerManager;
-use controller::{Controller, PairingState};
-use virtual_controller::key_map::{self, CombinedControllerKeyMap};
+use controller::{Controller, MotionDevice, PairingState};
+use pairing_memory::{Pairing, PairingMemory, PairingMode};
+use pairing_queue::{PairingQueue, Side};
 use waiting_controller_manager::WaitingControllerManager;
 
 use crate::{
-    key_allocator::KeyAllocator, poll_manager::PollManager, udev_detector::JoyconUdevDetector,
+    config::{Config, FeedbackEvent, LedFeedback, PairingTimeoutAction},
+    control::{ControlServer, Request, REQUEST_TIMEOUT},
+    key_allocator::KeyAllocator,
+    poll_manager::PollManager,
+    udev_detector::JoyconUdevDetector,
     UDEV_KEY,
 };
 
 use anyhow::{anyhow, Context, Result as Anyhow};
+use nix::sys::signal::Signal;
 
 mod combined_controller_manager;
 mod controller;
+mod model_registry;
+mod pairing_memory;
+mod pairing_queue;
+mod player_leds;
 mod virtual_controller;
 mod waiting_controller_manager;
 
+pub use controller::Button;
+pub use model_registry::{DeviceMatch, GestureAction, Model, ModelRegistry, Role};
+pub use virtual_controller::{
+    key_map, DeadzoneShape, KeyMap, ResponseCurve, RumbleRouting, StickSide,
+};
+
 const CONTROLLER_TOKEN_CAPACITY: usize = 0x100;
 
 #[allow(unused)]
@@ -27,19 +53,68 @@ pub enum ControllerMessage {
     UdevEvent(udev::Event),
     DeviceScan(udev::Device),
 
+    /// A disconnected controller has not reconnected to its group in time.
+    ReconnectTimeout(usize),
+
+    /// A controller has waited for a partner for too long.
+    PairingTimeout(usize),
+
+    /// The unpair gesture is held on every controller of a combined group, for the duration
+    /// dissolving it.
+    UnpairGestureHeld(usize, Duration),
+
+    /// The unpair gesture is no longer held on a combined group.
+    UnpairGestureReleased(usize),
+
+    /// The unpair gesture is held long enough on a combined group.
+    UnpairGesture(usize),
+
+    /// SIGTERM and SIGINT shut the daemon down, SIGHUP reloads the configuration.
+    Signal(Signal),
+
+    /// A connection accepted on the control socket, whose request is still to be read.
+    ControlConnection(UnixStream),
+
+    /// A request line read from a control connection, or the failure to read it.
+    ControlRequest(UnixStream, Anyhow<String>),
+
+    /// A control connection has not sent its request in time.
+    ControlTimeout(usize),
+
     Relay,
 }
 
 #[allow(unused)]
 pub struct ControllerManager {
+    config: Config,
+    config_path: PathBuf,
+    started: Instant,
+    running: bool,
+
     waiting_controller_manager: WaitingControllerManager,
     combined_controller_manager: CombinedControllerManager,
 
     controller_token_allocator: KeyAllocator,
     controller_token_map: HashMap<PathBuf, usize>,
 
-    left: Option<usize>,
-    right: Option<usize>,
+    /// Map HID syspaths to controller tokens, to link IMU devices to their controllers.
+    controller_hid_map: HashMap<PathBuf, usize>,
+    /// Map IMU devnodes to their HID syspaths.
+    motion_device_map: HashMap<PathBuf, PathBuf>,
+    /// IMU devices whose controllers have not been added yet, keyed by HID syspath.
+    pending_motion_devices: HashMap<PathBuf, MotionDevice>,
+
+    pairing_queue: PairingQueue,
+    /// Map the controllers waiting for a partner to their pairing timeout timers.
+    pairing_timers: HashMap<usize, usize>,
+    /// Map the `uniq`s of the controllers disconnected from their groups to their tokens and
+    /// reconnect timeout timers.
+    suspended: HashMap<String, (usize, usize)>,
+    /// Map the subscriptions of the control connections whose request is still to be read to
+    /// their fds and request timeout timers.
+    control_connections: HashMap<usize, (RawFd, usize)>,
+    pairing_memory: PairingMemory,
+    model_registry: ModelRegistry,
 }
 
 impl ControllerManager {
@@ -61,23 +136,42 @@ impl ControllerManager {
         Ok(())
     }
 
-    pub fn new() -> Self {
-        Self {
+    pub fn new(config: Config, config_path: PathBuf) -> Anyhow<Self> {
+        let model_registry = config.model_registry()?;
+        let state_file = &config.pairing.state_file;
+        let pairing_queue = PairingQueue::new(config.pairing.matching);
+        let pairing_memory = PairingMemory::load(state_file).unwrap_or_else(|e| {
+            eprintln!("{e:#}");
+            PairingMemory::empty(state_file)
+        });
+
+        Ok(Self {
+            config,
+            config_path,
+            started: Instant::now(),
+            running: true,
             waiting_controller_manager: WaitingControllerManager::new(),
             combined_controller_manager: CombinedControllerManager::new(),
             controller_token_allocator: KeyAllocator::new(CONTROLLER_TOKEN_CAPACITY),
             controller_token_map: HashMap::new(),
-            left: None,
-            right: None,
-        }
+            controller_hid_map: HashMap::new(),
+            motion_device_map: HashMap::new(),
+            pending_motion_devices: HashMap::new(),
+            pairing_queue,
+            pairing_timers: HashMap::new(),
+            suspended: HashMap::new(),
+            control_connections: HashMap::new(),
+            pairing_memory,
+            model_registry,
+        })
     }
 
     pub fn init(
         &mut self,
         poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
     ) -> Anyhow<()> {
-        let devices =
-            JoyconUdevDetector::enumerate().with_context(|| "Failed to scan the udev devices")?;
+        let devices = JoyconUdevDetector::enumerate(&self.config.udev_tag)
+            .with_context(|| "Failed to scan the udev devices")?;
         for msg in devices.into_iter().map(ControllerMessage::DeviceScan) {
             self.process(UDEV_KEY, poll_manager, msg)?
         }
@@ -87,7 +181,7 @@ impl ControllerManager {
 
     pub fn process(
         &mut self,
-        _callback_key: usize,
+        callback_key: usize,
         poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
         message: ControllerMessage,
     ) -> Anyhow<()> {
@@ -110,6 +204,93 @@ impl ControllerManager {
                 self.add_new_device(device, poll_manager)?;
             }
 
+            ControllerMessage::ReconnectTimeout(token) => {
+                if self
+                    .suspended
+                    .values()
+                    .any(|&(t, timer)| t == token && timer == callback_key)
+                {
+                    eprintln!("Controller {token} did not reconnect in time");
+                    self.forget_suspended(token, poll_manager)?;
+                    self.drop_combined_controller(token, poll_manager)?;
+                }
+            }
+
+            ControllerMessage::PairingTimeout(token) => {
+                // Ignore a timeout fired just before the controller left waiting.
+                if self.pairing_timers.get(&token) == Some(&callback_key) {
+                    self.expire_pairing(token, poll_manager)?;
+                }
+            }
+
+            ControllerMessage::UnpairGestureHeld(group, hold) => {
+                self.combined_controller_manager
+                    .hold_unpair_gesture(group, hold, poll_manager)?;
+            }
+
+            ControllerMessage::UnpairGestureReleased(group) => {
+                self.combined_controller_manager
+                    .release_unpair_gesture(group, poll_manager)?;
+            }
+
+            ControllerMessage::UnpairGesture(group) => {
+                // Ignore a timer fired just before the chord was released.
+                if self.combined_controller_manager.unpair_gesture_timer(group)
+                    == Some(callback_key)
+                {
+                    self.dissolve_group(group, poll_manager)?;
+                }
+            }
+
+            ControllerMessage::Signal(signal) => match signal {
+                Signal::SIGTERM | Signal::SIGINT => {
+                    eprintln!("Received {signal}, shutting down");
+                    self.running = false;
+                }
+                Signal::SIGHUP => self.reload_config()?,
+                _ => Err(anyhow!("Unhandled signal {signal}"))?,
+            },
+
+            ControllerMessage::ControlConnection(stream) => {
+                let fd = stream.as_raw_fd();
+                let key = poll_manager.subscribe(
+                    fd,
+                    polling::Event::readable(0),
+                    polling::PollMode::Level,
+                    Box::new(ControlServer::connection(stream)),
+                )?;
+                let timer = poll_manager.add_timer(
+                    REQUEST_TIMEOUT,
+                    None,
+                    Box::new(move |_ctx: &mut ControllerManager| {
+                        Ok(ControllerMessage::ControlTimeout(key))
+                    }),
+                )?;
+                self.control_connections.insert(key, (fd, timer));
+            }
+
+            ControllerMessage::ControlRequest(stream, line) => {
+                if let Some((_, timer)) = self.control_connections.remove(&callback_key) {
+                    poll_manager.remove_timer(timer)?;
+                }
+                poll_manager.remove(callback_key, &stream)?;
+                let response = line
+                    .and_then(|line| line.parse())
+                    .and_then(|request| self.handle_request(request, poll_manager));
+                ControlServer::respond(stream, response)?;
+            }
+
+            ControllerMessage::ControlTimeout(connection) => {
+                if self
+                    .control_connections
+                    .get(&connection)
+                    .is_some_and(|&(_, timer)| timer == callback_key)
+                {
+                    eprintln!("Control connection {connection} sent no request in time");
+                    self.close_control_connection(connection, poll_manager)?;
+                }
+            }
+
             ControllerMessage::Relay => {
                 // Do nothing.
             }
@@ -118,6 +299,115 @@ impl ControllerManager {
         Ok(())
     }
 
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Destroy all the combined groups, releasing everything held on their virtual controllers,
+    /// stop watching the controllers and remove the timers and control connections.
+    pub fn shutdown(
+        &mut self,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        for summary in self.combined_controller_manager.groups() {
+            if let Err(e) = self
+                .combined_controller_manager
+                .remove_group(summary.group, poll_manager)
+            {
+                eprintln!("{e}");
+            }
+        }
+        for (token, _) in self.waiting_controller_manager.controllers() {
+            if let Err(e) = self.leave_waiting(token, poll_manager).and_then(|_| {
+                self.waiting_controller_manager
+                    .remove_device(token, poll_manager)
+            }) {
+                eprintln!("{e}");
+            }
+        }
+        let timers = self
+            .pairing_timers
+            .drain()
+            .map(|(_, timer)| timer)
+            .chain(self.suspended.drain().map(|(_, (_, timer))| timer))
+            .collect::<Vec<_>>();
+        for timer in timers {
+            if let Err(e) = poll_manager.remove_timer(timer) {
+                eprintln!("{e}");
+            }
+        }
+        let connections: Vec<_> = self.control_connections.keys().copied().collect();
+        for connection in connections {
+            if let Err(e) = self.close_control_connection(connection, poll_manager) {
+                eprintln!("{e}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Close a control connection whose request is still to be read.
+    fn close_control_connection(
+        &mut self,
+        connection: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        let (fd, timer) = self
+            .control_connections
+            .remove(&connection)
+            .ok_or_else(|| anyhow!("No control connection {connection}"))?;
+        poll_manager.remove_timer(timer)?;
+
+        // # Safety
+        //
+        // The connection is open until its callback drops, after it is deleted from the poller.
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        poll_manager.remove(connection, fd)
+    }
+
+    /// Reload the configuration file. The changed key maps of the combined groups are swapped and
+    /// the rumble intensity and routing are updated, except the routing of the groups changed from
+    /// the control socket. The other settings, including the models, only apply to the groups
+    /// formed and the controllers added afterwards. The udev tag, sysfs root, control socket and
+    /// state file are only read at startup.
+    fn reload_config(&mut self) -> Anyhow<()> {
+        let config = Config::load(&self.config_path)
+            .with_context(|| "Failed to reload the configuration, keeping the current one")?;
+        for summary in self.combined_controller_manager.groups() {
+            if self.config.same_key_map(&config, &summary.key_map) {
+                continue;
+            }
+            if let Err(e) = self.combined_controller_manager.set_key_map(
+                summary.group,
+                &summary.key_map,
+                &config,
+            ) {
+                eprintln!("Keeping the key map of group {}: {e:#}", summary.group);
+            }
+        }
+        for summary in self.combined_controller_manager.groups() {
+            if summary.rumble_routing == self.config.rumble.routing
+                && config.rumble.routing != self.config.rumble.routing
+            {
+                self.combined_controller_manager
+                    .set_rumble_routing(summary.group, config.rumble.routing)?;
+            }
+            if config.rumble.intensity != self.config.rumble.intensity {
+                self.combined_controller_manager
+                    .set_rumble_intensity(summary.group, config.rumble.intensity)?;
+            }
+        }
+        self.pairing_queue.set_matching(config.pairing.matching);
+        self.model_registry = config.model_registry()?;
+        self.config = config;
+        eprintln!(
+            "Reloaded the configuration from {}",
+            self.config_path.display()
+        );
+
+        Ok(())
+    }
+
     /// Add a new controller to the controller manager and generate a token for it. The new controller will be added to the
     /// waiting controller manager.
     fn add_new_device(
@@ -125,17 +415,53 @@ impl ControllerManager {
         device: udev::Device,
         poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
     ) -> Anyhow<()> {
+        if JoyconUdevDetector::is_motion_device(&device) {
+            return self.add_motion_device(device, poll_manager);
+        }
+
         let new_key = self.controller_token_allocator.allocate()?;
         let devname = device
             .devnode()
             .ok_or_else(|| anyhow::anyhow!("Cannot get devnode of {:?}", device.devpath()))?;
         self.controller_token_map
             .insert(devname.to_path_buf(), new_key);
-        let controller = Rc::new(RefCell::new(Controller::new(device)?));
-        self.waiting_controller_manager
-            .add_new_device(new_key, controller, poll_manager)?;
+        let hid_syspath = JoyconUdevDetector::hid_syspath(&device);
+        let controller = Rc::new(RefCell::new(Controller::new(
+            device,
+            &self.model_registry,
+            &self.config.sysfs_root,
+        )?));
 
-        Ok(())
+        match hid_syspath {
+            Ok(hid_syspath) => {
+                if let Some(motion_device) = self.pending_motion_devices.remove(&hid_syspath) {
+                    controller.borrow_mut().attach_motion_device(motion_device);
+                }
+                self.controller_hid_map.insert(hid_syspath, new_key);
+            }
+            Err(e) => eprintln!("{e}"),
+        }
+
+        // Put a controller reconnecting in time back in its group.
+        let uniq = controller.borrow().uniq().map(str::to_string);
+        if let Some(suspended_token) = self.take_suspended(uniq.as_deref(), poll_manager)? {
+            let group = self.combined_controller_manager.resume_controller(
+                suspended_token,
+                (new_key, controller),
+                poll_manager,
+            )?;
+            eprintln!("Controller {new_key} reconnected to group {group}");
+            return Ok(());
+        }
+
+        self.waiting_controller_manager.add_new_device(
+            new_key,
+            controller.clone(),
+            poll_manager,
+        )?;
+        self.give_feedback(&controller, FeedbackEvent::Reset, None);
+
+        self.recall_pairing(new_key, poll_manager)
     }
 
     /// Remove a controller.
@@ -147,119 +473,683 @@ impl ControllerManager {
         let devpath = device
             .devnode()
             .ok_or_else(|| anyhow::anyhow!("Cannot get devnode of {:?}", device.devpath()))?;
+        if self.motion_device_map.contains_key(devpath) {
+            return self.remove_motion_device(device, poll_manager);
+        }
+
         let &token = self
             .controller_token_map
             .get(devpath)
             .ok_or_else(|| anyhow::anyhow!("Cannot get token of {:?}", device.devpath()))?;
 
-        let collected = if self
+        self.controller_hid_map.retain(|_, t| *t != token);
+        self.leave_waiting(token, poll_manager)?;
+        if self
             .waiting_controller_manager
             .remove_device(token, poll_manager)?
             .is_some()
+            || self.suspend_controller(token, poll_manager)?
         {
-            Some(vec![])
-        } else {
-            self.combined_controller_manager
-                .remove_device(token, poll_manager)?
+            return Ok(());
+        }
+
+        self.drop_combined_controller(token, poll_manager)
+    }
+
+    /// Keep the group of a disconnected controller for the reconnect grace period. Return whether
+    /// the controller is suspended.
+    fn suspend_controller(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<bool> {
+        let grace_ms = self.config.reconnect.grace_ms;
+        if grace_ms == 0 {
+            return Ok(false);
+        }
+        let Some(controller) = self.combined_controller_manager.get_controller(token) else {
+            return Ok(false);
+        };
+        let Some(uniq) = controller.borrow().uniq().map(str::to_string) else {
+            return Ok(false);
         };
 
-        if let Some(controllers) = collected {
-            for (token, controller) in controllers {
-                self.waiting_controller_manager
-                    .add_new_device(token, controller, poll_manager)?
+        let group = self
+            .combined_controller_manager
+            .suspend_controller(token, poll_manager)?;
+        let timer = poll_manager.add_timer(
+            Duration::from_millis(grace_ms),
+            None,
+            Box::new(move |_ctx: &mut ControllerManager| {
+                Ok(ControllerMessage::ReconnectTimeout(token))
+            }),
+        )?;
+        self.suspended.insert(uniq, (token, timer));
+        eprintln!("Controller {token} disconnected, group {group} waits {grace_ms} ms for it");
+
+        Ok(true)
+    }
+
+    /// The token of the suspended controller a controller reconnecting with `uniq` replaces, if
+    /// any. The controller is no longer waited for.
+    fn take_suspended(
+        &mut self,
+        uniq: Option<&str>,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<Option<usize>> {
+        let Some((token, timer)) = uniq.and_then(|uniq| self.suspended.remove(uniq)) else {
+            return Ok(None);
+        };
+        poll_manager.remove_timer(timer)?;
+
+        Ok(Some(token))
+    }
+
+    /// Stop waiting for a suspended controller to reconnect. Return whether it was suspended.
+    fn forget_suspended(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<bool> {
+        let Some(uniq) = self
+            .suspended
+            .iter()
+            .find(|(_, &(t, _))| t == token)
+            .map(|(uniq, _)| uniq.clone())
+        else {
+            return Ok(false);
+        };
+        if let Some((_, timer)) = self.suspended.remove(&uniq) {
+            poll_manager.remove_timer(timer)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Remove a controller from its group, dissolving the group and moving the other controllers
+    /// back to the waiting controllers.
+    fn drop_combined_controller(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        let (controllers, suspended) = self
+            .combined_controller_manager
+            .remove_device(token, poll_manager)?
+            .ok_or_else(|| anyhow::anyhow!("Token {token} cannot be found in neither waiting controllers nor combined controllers"))?;
+
+        self.return_to_waiting(controllers, suspended, poll_manager)
+    }
+
+    /// Move the controllers of a dissolved group back to the waiting controllers, and stop
+    /// waiting for its `suspended` controllers to reconnect.
+    fn return_to_waiting(
+        &mut self,
+        controllers: Vec<(usize, Rc<RefCell<Controller>>)>,
+        suspended: Vec<usize>,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        for token in suspended {
+            self.forget_suspended(token, poll_manager)?;
+        }
+        for (token, controller) in controllers {
+            self.waiting_controller_manager.add_new_device(
+                token,
+                controller.clone(),
+                poll_manager,
+            )?;
+            self.give_feedback(&controller, FeedbackEvent::Reset, None);
+        }
+
+        Ok(())
+    }
+
+    /// Add an IMU device and link it to its controller. If the controller has not been added
+    /// yet, the IMU device is kept until it is.
+    ///
+    /// The motion data of a controller is only relayed by the groups having a motion device, that
+    /// is, formed with at least one IMU device linked.
+    fn add_motion_device(
+        &mut self,
+        device: udev::Device,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        let devname = device
+            .devnode()
+            .ok_or_else(|| anyhow::anyhow!("Cannot get devnode of {:?}", device.devpath()))?
+            .to_path_buf();
+        let hid_syspath = JoyconUdevDetector::hid_syspath(&device)?;
+        let motion_device = MotionDevice::new(device)?;
+        self.motion_device_map.insert(devname, hid_syspath.clone());
+
+        match self.controller_hid_map.get(&hid_syspath) {
+            Some(&token) => {
+                if let Ok(controller) = self.waiting_controller_manager.get_controller(token) {
+                    controller.borrow_mut().attach_motion_device(motion_device);
+                } else {
+                    let controller = self
+                        .combined_controller_manager
+                        .get_controller(token)
+                        .ok_or_else(|| anyhow::anyhow!("No controller for token {token}"))?;
+                    controller.borrow_mut().attach_motion_device(motion_device);
+                    self.combined_controller_manager
+                        .attach_motion_device(token, poll_manager)?;
+                }
+            }
+            None => {
+                self.pending_motion_devices
+                    .insert(hid_syspath, motion_device);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove an IMU device and unlink it from its controller.
+    fn remove_motion_device(
+        &mut self,
+        device: udev::Device,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        let devname = device
+            .devnode()
+            .ok_or_else(|| anyhow::anyhow!("Cannot get devnode of {:?}", device.devpath()))?;
+        let hid_syspath = self
+            .motion_device_map
+            .remove(devname)
+            .ok_or_else(|| anyhow::anyhow!("Cannot get HID syspath of {:?}", device.devpath()))?;
+
+        if self.pending_motion_devices.remove(&hid_syspath).is_some() {
+            return Ok(());
+        }
+
+        // The controller may be removed before its IMU device.
+        if let Some(&token) = self.controller_hid_map.get(&hid_syspath) {
+            if let Ok(controller) = self.waiting_controller_manager.get_controller(token) {
+                controller.borrow_mut().detach_motion_device();
+            } else {
+                self.combined_controller_manager
+                    .detach_motion_device(token, poll_manager)?;
             }
-        } else {
-            Err(anyhow::anyhow!("Token {token} cannot be found in neither waiting controllers nor combined controllers"))?
         }
 
         Ok(())
     }
 
-    /// Update the controller manager when receiving paring states.
+    /// Update the controller manager when receiving paring states. A failure is signaled on the
+    /// controller.
     fn update_pairing_state(
         &mut self,
         controller_token: usize,
         pairing_state: PairingState,
         poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        let result = self.apply_pairing_state(controller_token, pairing_state, poll_manager);
+        if result.is_err() {
+            if let Ok(controller) = self
+                .waiting_controller_manager
+                .get_controller(controller_token)
+            {
+                self.give_feedback(&controller, FeedbackEvent::Error, None);
+            }
+        }
+
+        result
+    }
+
+    fn apply_pairing_state(
+        &mut self,
+        controller_token: usize,
+        pairing_state: PairingState,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
     ) -> Anyhow<()> {
         match pairing_state {
             PairingState::Pairing => {
                 // Do nothing.
             }
             PairingState::Waiting(model) => {
-                // Store the controller token and corresponding model. Combine the controllers
-                // when both left and right controllers are entering waiting state.
-                match model {
-                    controller::Model::LeftJoycon => self.left = Some(controller_token),
-                    controller::Model::RightJoycon => self.right = Some(controller_token),
-                }
-
-                if self.left.is_some() && self.right.is_some() {
-                    let (left_token, right_token) = (
-                        self.left
-                            .take()
-                            .ok_or_else(|| anyhow::anyhow!("Left token is None"))?,
-                        self.right
-                            .take()
-                            .ok_or_else(|| anyhow::anyhow!("Right token is None"))?,
-                    );
-
-                    let (left_controller, right_controller) = (
-                        self.waiting_controller_manager.get_controller(left_token)?,
-                        self.waiting_controller_manager
-                            .get_controller(right_token)?,
-                    );
-
-                    self.waiting_controller_manager
-                        .remove_device(left_token, poll_manager)?;
-                    self.waiting_controller_manager
-                        .remove_device(right_token, poll_manager)?;
-
-                    self.combined_controller_manager.add_new_devices(
-                        vec![
-                            (left_token, left_controller),
-                            (right_token, right_controller),
-                        ],
-                        Box::new(CombinedControllerKeyMap::new()),
-                        poll_manager,
-                    )?;
+                // Queue the controller until a controller of the other side is waiting too, then
+                // combine them.
+                if !self.pairing_queue.contains(controller_token) {
+                    let side = if model.is_left() {
+                        Side::Left
+                    } else if model.is_right() {
+                        Side::Right
+                    } else {
+                        Err(anyhow!("A {model} cannot wait for a partner"))?
+                    };
+                    match self.pairing_queue.push(controller_token, side) {
+                        Some((left, right)) => {
+                            self.pair(left, right, poll_manager)?;
+                        }
+                        None => self.start_waiting(controller_token, poll_manager)?,
+                    }
                 }
             }
 
             // Push the controller into combined controller manager and configure it with
             // corresponding key map.
             PairingState::Lone => {
-                let controller = self
-                    .waiting_controller_manager
-                    .get_controller(controller_token)?;
-                self.waiting_controller_manager
-                    .remove_device(controller_token, poll_manager)?;
-                self.combined_controller_manager.add_new_devices(
-                    vec![(controller_token, controller)],
-                    Box::new(key_map::LoneConstrollerKeyMap::new()),
-                    poll_manager,
-                )?;
+                self.make_lone(controller_token, poll_manager)?;
             }
             PairingState::Horizontal => {
-                let controller = self
-                    .waiting_controller_manager
-                    .get_controller(controller_token)?;
+                self.make_horizontal(controller_token, poll_manager)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Combine two waiting controllers, a left one and a right one, in either order. Return the
+    /// group token.
+    pub fn pair(
+        &mut self,
+        first: usize,
+        second: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<usize> {
+        let first_model = self.waiting_model(first)?;
+        let second_model = self.waiting_model(second)?;
+        // Keep the left controller first, as the key maps expect.
+        let tokens = match (first_model.is_left(), second_model.is_left()) {
+            (true, false) => vec![first, second],
+            (false, true) => vec![second, first],
+            _ => Err(anyhow!(
+                "Cannot pair {first} and {second}: a left and a right controller are required"
+            ))?,
+        };
+
+        let key_map_name = self.config.key_maps.combined.clone();
+        self.combine_controllers(tokens, PairingMode::Combined, &key_map_name, poll_manager)
+    }
+
+    /// Use a waiting controller alone, held vertically. Return the group token.
+    pub fn make_lone(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<usize> {
+        self.waiting_model(token)?;
+        let key_map_name = self.config.key_maps.lone.clone();
+        self.combine_controllers(vec![token], PairingMode::Lone, &key_map_name, poll_manager)
+    }
+
+    /// Use a waiting controller alone, held horizontally. Return the group token.
+    pub fn make_horizontal(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<usize> {
+        let model = self.waiting_model(token)?;
+        let key_map_name = match model.role {
+            Role::Left if model.can_be_horizontal() => self.config.key_maps.horizontal_left.clone(),
+            Role::Right if model.can_be_horizontal() => {
+                self.config.key_maps.horizontal_right.clone()
+            }
+            _ => Err(anyhow!("A {model} cannot be held horizontally"))?,
+        };
+        self.combine_controllers(
+            vec![token],
+            PairingMode::Horizontal,
+            &key_map_name,
+            poll_manager,
+        )
+    }
+
+    fn waiting_model(&self, token: usize) -> Anyhow<Rc<Model>> {
+        let controller = self.waiting_controller_manager.get_controller(token)?;
+        let model = controller.borrow().get_model();
+        Ok(model)
+    }
+
+    /// Move waiting controllers into a new combined group and remember it. Return the group token.
+    fn combine_controllers(
+        &mut self,
+        tokens: Vec<usize>,
+        mode: PairingMode,
+        key_map_name: &str,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<usize> {
+        let controllers = tokens
+            .iter()
+            .map(|&token| {
                 self.waiting_controller_manager
-                    .remove_device(controller_token, poll_manager)?;
-                let key_map = match controller.borrow().get_model() {
-                    controller::Model::LeftJoycon => key_map::HorizontalLeftControllerKeyMap::new(),
-                    controller::Model::RightJoycon => {
-                        key_map::HorizontalRightControllerKeyMap::new()
-                    }
-                };
-                self.combined_controller_manager.add_new_devices(
-                    vec![(controller_token, controller)],
-                    Box::new(key_map),
-                    poll_manager,
-                )?;
+                    .get_controller(token)
+                    .map(|controller| (token, controller))
+            })
+            .collect::<Anyhow<Vec<_>>>()?;
+
+        for &token in &tokens {
+            self.waiting_controller_manager
+                .remove_device(token, poll_manager)?;
+            self.leave_waiting(token, poll_manager)?;
+        }
+
+        let uniqs: Option<Vec<_>> = controllers
+            .iter()
+            .map(|(_, controller)| controller.borrow().uniq().map(str::to_string))
+            .collect();
+        let group = self.combined_controller_manager.add_new_devices(
+            controllers.clone(),
+            key_map_name,
+            &self.config,
+            poll_manager,
+        )?;
+
+        let event = match mode {
+            PairingMode::Combined => FeedbackEvent::Combined,
+            PairingMode::Lone => FeedbackEvent::Lone,
+            PairingMode::Horizontal => FeedbackEvent::Horizontal,
+        };
+        let player = self.combined_controller_manager.player(group);
+        for (_, controller) in &controllers {
+            self.give_feedback(controller, event, player);
+        }
+
+        if let Some(controllers) = uniqs.filter(|_| self.config.pairing.remember) {
+            if let Err(e) = self.pairing_memory.remember(Pairing { mode, controllers }) {
+                eprintln!("{e:#}");
             }
         }
 
+        Ok(group)
+    }
+
+    /// Form the remembered group of a new waiting controller if all of its controllers are
+    /// waiting.
+    fn recall_pairing(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        if !self.config.pairing.remember {
+            return Ok(());
+        }
+        let controller = self.waiting_controller_manager.get_controller(token)?;
+        let Some(pairing) = controller
+            .borrow()
+            .uniq()
+            .and_then(|uniq| self.pairing_memory.find(uniq))
+            .cloned()
+        else {
+            return Ok(());
+        };
+
+        let waiting = self.waiting_controller_manager.controllers();
+        let tokens: Option<Vec<_>> = pairing
+            .controllers
+            .iter()
+            .map(|uniq| {
+                waiting
+                    .iter()
+                    .find(|(_, controller)| controller.borrow().uniq() == Some(uniq))
+                    .map(|(token, _)| *token)
+            })
+            .collect();
+        let Some(tokens) = tokens else {
+            return Ok(());
+        };
+
+        eprintln!(
+            "Forming the remembered {:?} group of controllers {tokens:?}",
+            pairing.mode
+        );
+        match (pairing.mode, tokens.as_slice()) {
+            (PairingMode::Combined, &[left, right]) => self.pair(left, right, poll_manager)?,
+            (PairingMode::Lone, &[token]) => self.make_lone(token, poll_manager)?,
+            (PairingMode::Horizontal, &[token]) => self.make_horizontal(token, poll_manager)?,
+            _ => Err(anyhow!("Invalid remembered pairing {pairing:?}"))?,
+        };
+
         Ok(())
     }
+
+    /// Forget the remembered pairing of the controllers of a group.
+    fn forget_group(&mut self, group: usize) -> Anyhow<()> {
+        let summary = self
+            .combined_controller_manager
+            .groups()
+            .into_iter()
+            .find(|summary| summary.group == group)
+            .ok_or_else(|| anyhow!("No combined group {group}"))?;
+        for (_, controller) in summary.controllers {
+            if let Some(uniq) = controller.borrow().uniq() {
+                self.pairing_memory.forget(uniq)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start the pairing timeout of a controller waiting for a partner.
+    fn start_waiting(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        eprintln!("Controller {token} is waiting for a partner");
+        let controller = self.waiting_controller_manager.get_controller(token)?;
+        self.give_feedback(&controller, FeedbackEvent::Waiting, None);
+        if self.config.pairing.timeout_ms > 0 {
+            let timeout = Duration::from_millis(self.config.pairing.timeout_ms);
+            let timer = poll_manager.add_timer(
+                timeout,
+                None,
+                Box::new(move |_ctx: &mut ControllerManager| {
+                    Ok(ControllerMessage::PairingTimeout(token))
+                }),
+            )?;
+            self.pairing_timers.insert(token, timer);
+        }
+
+        Ok(())
+    }
+
+    /// Signal a pairing transition on a controller with a rumble and its LEDs. `player` is the
+    /// player number of the group formed, if any.
+    fn give_feedback(
+        &self,
+        controller: &Rc<RefCell<Controller>>,
+        event: FeedbackEvent,
+        player: Option<usize>,
+    ) {
+        let pattern = self.config.feedback.pattern(event);
+        let mut controller = controller.borrow_mut();
+        if let Some(leds) = controller.leds() {
+            let result = match (pattern.leds, player) {
+                (LedFeedback::Blink, _) => leds.blink(),
+                (LedFeedback::BlinkFast, _) => leds.blink_fast(),
+                (LedFeedback::Player, Some(player)) => leds.set_player(player),
+                // Groups beyond the maximum number of players have no player number.
+                (LedFeedback::Player, None) | (LedFeedback::Keep, _) => Ok(()),
+            };
+            if let Err(e) = result {
+                eprintln!("{e}");
+            }
+        }
+        let pulse = Duration::from_millis(pattern.pulse_ms.into());
+        if let Err(e) = controller.rumble(pattern.pulses, pulse) {
+            eprintln!("{e}");
+        }
+    }
+
+    /// Forget that a controller is waiting for a partner.
+    fn leave_waiting(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        self.pairing_queue.remove(token);
+        if let Some(timer) = self.pairing_timers.remove(&token) {
+            poll_manager.remove_timer(timer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a waiting controller back to pairing.
+    fn reset_pairing(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        self.leave_waiting(token, poll_manager)?;
+        let controller = self.waiting_controller_manager.get_controller(token)?;
+        controller.borrow_mut().reset_buttons_state();
+        self.give_feedback(&controller, FeedbackEvent::Reset, None);
+
+        Ok(())
+    }
+
+    /// Apply the timeout action to a controller waiting for a partner for too long.
+    fn expire_pairing(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        match self.config.pairing.on_timeout {
+            PairingTimeoutAction::Lone => {
+                eprintln!("Controller {token} found no partner, using it alone");
+                self.make_lone(token, poll_manager)?;
+            }
+            PairingTimeoutAction::Reset => {
+                eprintln!("Controller {token} found no partner, back to pairing");
+                self.reset_pairing(token, poll_manager)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Dissolve a combined group and move its controllers back to the waiting controllers.
+    pub fn dissolve_group(
+        &mut self,
+        group: usize,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        if let Err(e) = self.forget_group(group) {
+            eprintln!("{e:#}");
+        }
+        let (controllers, suspended) = self
+            .combined_controller_manager
+            .remove_group(group, poll_manager)?;
+
+        self.return_to_waiting(controllers, suspended, poll_manager)
+    }
+
+    /// Handle a request from the control socket, returning the response body.
+    fn handle_request(
+        &mut self,
+        request: Request,
+        poll_manager: &mut PollManager<Self, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<String> {
+        match request {
+            Request::Version => Ok(format!("joycombinerd {}", env!("CARGO_PKG_VERSION"))),
+            Request::Uptime => Ok(format!("{}", self.started.elapsed().as_secs())),
+            Request::List => {
+                let mut lines = vec![];
+                for (token, controller) in self.waiting_controller_manager.controllers() {
+                    lines.push(format!(
+                        "waiting {token} {}",
+                        controller.borrow().get_model()
+                    ));
+                }
+                for summary in self.combined_controller_manager.groups() {
+                    let player = summary
+                        .player
+                        .map_or("-".to_string(), |player| player.to_string());
+                    let controllers: Vec<_> = summary
+                        .controllers
+                        .iter()
+                        .map(|(token, controller)| {
+                            format!("{token}:{}", controller.borrow().get_model())
+                        })
+                        .collect();
+                    lines.push(format!(
+                        "group {} player {player} keymap {} rumble {} controllers {}",
+                        summary.group,
+                        summary.key_map,
+                        summary.rumble_routing,
+                        controllers.join(",")
+                    ));
+                }
+
+                Ok(lines.join("\n"))
+            }
+            Request::Pair(first, second) => {
+                let group = self.pair(first, second, poll_manager)?;
+                Ok(format!("group {group}"))
+            }
+            Request::Lone(token) => {
+                let group = self.make_lone(token, poll_manager)?;
+                Ok(format!("group {group}"))
+            }
+            Request::Horizontal(token) => {
+                let group = self.make_horizontal(token, poll_manager)?;
+                Ok(format!("group {group}"))
+            }
+            Request::Unpair(group) => {
+                self.dissolve_group(group, poll_manager)?;
+                Ok(String::new())
+            }
+            Request::Forget(group) => {
+                self.forget_group(group)?;
+                Ok(String::new())
+            }
+            Request::Reload => {
+                self.reload_config()?;
+                Ok(String::new())
+            }
+            Request::KeyMap(group, name) => {
+                self.combined_controller_manager
+                    .set_key_map(group, &name, &self.config)?;
+                Ok(String::new())
+            }
+            Request::Rumble(group, routing) => {
+                self.combined_controller_manager
+                    .set_rumble_routing(group, routing)?;
+                Ok(String::new())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNIQ: &str = "aa:bb:cc:dd:ee:ff";
+
+    #[test]
+    fn dissolve_group_with_suspended_half() {
+        let mut config = Config::default();
+        config.pairing.state_file = std::env::temp_dir().join("joycombinerd-no-state.toml");
+        let mut manager = ControllerManager::new(config, PathBuf::new()).unwrap();
+        let mut poll_manager = PollManager::new().unwrap();
+
+        // Controller 1 disconnected from its group and is waited for.
+        let timer = poll_manager
+            .add_timer(
+                Duration::from_secs(60),
+                None,
+                Box::new(|_ctx: &mut ControllerManager| Ok(ControllerMessage::Relay)),
+            )
+            .unwrap();
+        manager.suspended.insert(UNIQ.to_string(), (1, timer));
+
+        // The group is dissolved before it reconnects.
+        manager
+            .return_to_waiting(vec![], vec![1], &mut poll_manager)
+            .unwrap();
+        assert!(manager.suspended.is_empty());
+        assert!(poll_manager.remove_timer(timer).is_err());
+
+        // Reconnecting, it is a new controller rather than the half of a group that is gone.
+        assert_eq!(
+            manager
+                .take_suspended(Some(UNIQ), &mut poll_manager)
+                .unwrap(),
+            None
+        );
+    }
 }
@@ -1,11 +1,17 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    time::Duration,
+};
 
 use super::{
-    controller::Controller,
-    virtual_controller::{KeyMap, VirtualController},
+    controller::{Controller, MotionDevice},
+    player_leds::MAX_PLAYERS,
+    virtual_controller::{GestureChange, RumbleRouting, VirtualController},
     ControllerManager, ControllerMessage,
 };
-use crate::{key_allocator::KeyAllocator, poll_manager::PollManager};
+use crate::{config::Config, key_allocator::KeyAllocator, poll_manager::PollManager};
 
 use anyhow::Result as Anyhow;
 
@@ -13,6 +19,8 @@ const COMBINED_GROUP_CAPACITY: usize = 0x100;
 
 type TokenController = (usize, Rc<RefCell<Controller>>);
 type TokenControllers = Vec<TokenController>;
+/// The connected controllers of a dissolved group and the tokens of its suspended ones.
+type DissolvedGroup = (TokenControllers, Vec<usize>);
 type CallbackTokenController = (usize, TokenController);
 type CallbackTokenControllers = Vec<CallbackTokenController>;
 type CallbackVirtualController = (
@@ -21,10 +29,30 @@ type CallbackVirtualController = (
     CallbackTokenControllers,
 );
 
+/// A summary of a combined group, for reporting.
+pub struct GroupSummary {
+    pub group: usize,
+    pub player: Option<usize>,
+    pub key_map: String,
+    pub rumble_routing: RumbleRouting,
+    pub controllers: TokenControllers,
+}
+
 pub struct CombinedControllerManager {
     combined_group_token_allocator: KeyAllocator,
     controller_groups: HashMap<usize, usize>,
     groups: HashMap<usize, CallbackVirtualController>,
+    motion_callbacks: HashMap<usize, usize>,
+    /// Map the groups to the callbacks of the force feedback engines of their virtual controllers.
+    ff_engine_callbacks: HashMap<usize, usize>,
+    /// Controllers disconnected from their groups, waiting to reconnect.
+    suspended: HashSet<usize>,
+    /// Map the groups whose unpair gesture is held to the timers dissolving them.
+    gesture_timers: HashMap<usize, usize>,
+
+    player_allocator: KeyAllocator,
+    group_players: HashMap<usize, usize>,
+    group_key_maps: HashMap<usize, String>,
 }
 
 impl CombinedControllerManager {
@@ -33,6 +61,13 @@ impl CombinedControllerManager {
             combined_group_token_allocator: KeyAllocator::new(COMBINED_GROUP_CAPACITY),
             controller_groups: HashMap::new(),
             groups: HashMap::new(),
+            motion_callbacks: HashMap::new(),
+            ff_engine_callbacks: HashMap::new(),
+            suspended: HashSet::new(),
+            gesture_timers: HashMap::new(),
+            player_allocator: KeyAllocator::new(MAX_PLAYERS),
+            group_players: HashMap::new(),
+            group_key_maps: HashMap::new(),
         }
     }
 
@@ -40,9 +75,11 @@ impl CombinedControllerManager {
     pub fn add_new_devices(
         &mut self,
         controllers: Vec<(usize, Rc<RefCell<Controller>>)>,
-        keymap: Box<dyn KeyMap>,
+        key_map_name: &str,
+        config: &Config,
         poll_manager: &mut PollManager<ControllerManager, Anyhow<ControllerMessage>>,
-    ) -> Anyhow<()> {
+    ) -> Anyhow<usize> {
+        let keymap = config.key_map(key_map_name)?;
         let new_group = self.combined_group_token_allocator.allocate()?;
         for controller_token in controllers.iter().map(|controller| controller.0) {
             self.controller_groups.insert(controller_token, new_group);
@@ -54,6 +91,7 @@ impl CombinedControllerManager {
                 .map(|(_, controller)| controller.clone())
                 .collect(),
             keymap,
+            config,
         )?;
         let virtual_controller = Rc::new(RefCell::new(virtual_controller));
         let callback = Box::new({
@@ -70,62 +108,463 @@ impl CombinedControllerManager {
             callback,
         )?;
 
+        let ff_engine_callback_key = poll_manager.subscribe(
+            virtual_controller.borrow().ff_engine(),
+            polling::Event::readable(0),
+            polling::PollMode::Level,
+            Box::new({
+                let virtual_controller = virtual_controller.clone();
+                move |_ctx: &mut ControllerManager| {
+                    virtual_controller.borrow_mut().run_ff_engine()?;
+                    Ok(ControllerMessage::Relay)
+                }
+            }),
+        )?;
+        self.ff_engine_callbacks
+            .insert(new_group, ff_engine_callback_key);
+
         let mut sub_controllers = vec![];
         for (id, (token, controller)) in controllers.iter().enumerate() {
-            let sub_controller = controller.clone();
-            let virtual_controller = virtual_controller.clone();
-            let callback = Box::new(move |_ctx: &mut ControllerManager| {
-                virtual_controller.borrow_mut().relay_input_events(id)?;
-                Ok(ControllerMessage::Relay)
-            });
-
-            let callback_key = poll_manager.subscribe(
-                &*controller.borrow(),
-                polling::Event::readable(0),
-                polling::PollMode::Level,
-                callback,
+            let callback_key = self.subscribe_controller(
+                new_group,
+                id,
+                (*token, controller.clone()),
+                &virtual_controller,
+                poll_manager,
             )?;
-            sub_controllers.push((callback_key, (*token, sub_controller)));
+            sub_controllers.push((callback_key, (*token, controller.clone())));
+        }
+
+        // Groups beyond the maximum number of players have no player number. The controllers
+        // show it as feedback of the pairing.
+        if let Ok(player) = self.player_allocator.allocate() {
+            self.group_players.insert(new_group, player);
         }
 
         self.groups.insert(
             new_group,
             (callback_key, virtual_controller, sub_controllers),
         );
+        self.group_key_maps
+            .insert(new_group, key_map_name.to_string());
 
-        Ok(())
+        Ok(new_group)
     }
 
     pub fn remove_device(
         &mut self,
         remove_token: usize,
         poll_manager: &mut PollManager<ControllerManager, Anyhow<ControllerMessage>>,
-    ) -> Anyhow<Option<TokenControllers>> {
-        if let Some(group) = self.controller_groups.get(&remove_token) {
-            let (callback_key, virtual_controller, sub_controllers) =
-                self.groups.remove(group).ok_or_else(|| {
-                    anyhow::anyhow!("Failed to get combined group info for group {group}")
-                })?;
-            self.combined_group_token_allocator.release(*group);
-
-            // Remove virtual controller subscribtion.
-            poll_manager.remove(callback_key, &*virtual_controller.borrow())?;
-
-            // Remove each controllers subscribtion and collect controllers except the one to be
-            // removed.
-            let mut collected = vec![];
-            for (callback_key, (token, controller)) in sub_controllers {
-                poll_manager.remove(callback_key, &*controller.borrow())?;
-                self.controller_groups.remove(&token);
-
-                if token != remove_token {
-                    collected.push((token, controller));
-                }
-            }
+    ) -> Anyhow<Option<DissolvedGroup>> {
+        if let Some(&group) = self.controller_groups.get(&remove_token) {
+            // Collect controllers except the one to be removed.
+            let (collected, suspended) = self.remove_group(group, poll_manager)?;
+            let collected = collected
+                .into_iter()
+                .filter(|(token, _)| *token != remove_token)
+                .collect();
 
-            Ok(Some(collected))
+            Ok(Some((collected, suspended)))
         } else {
             Ok(None)
         }
     }
+
+    /// Dissolve a group, destroying its virtual controller. Return its connected controllers and
+    /// the tokens of its suspended ones, which are no longer suspended.
+    pub fn remove_group(
+        &mut self,
+        group: usize,
+        poll_manager: &mut PollManager<ControllerManager, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<DissolvedGroup> {
+        let (callback_key, virtual_controller, sub_controllers) =
+            self.groups.remove(&group).ok_or_else(|| {
+                anyhow::anyhow!("Failed to get combined group info for group {group}")
+            })?;
+        self.combined_group_token_allocator.release(group);
+        self.group_key_maps.remove(&group);
+        self.release_unpair_gesture(group, poll_manager)?;
+        if let Some(player) = self.group_players.remove(&group) {
+            self.player_allocator.release(player);
+        }
+
+        // Release everything held before the virtual controller is gone.
+        if let Err(e) = virtual_controller.borrow_mut().reset_state() {
+            eprintln!("{e}");
+        }
+
+        // Remove virtual controller subscribtion.
+        poll_manager.remove(callback_key, &*virtual_controller.borrow())?;
+        if let Some(callback_key) = self.ff_engine_callbacks.remove(&group) {
+            poll_manager.remove(callback_key, virtual_controller.borrow().ff_engine())?;
+        }
+
+        // Remove each controllers subscribtion.
+        let mut collected = vec![];
+        let mut suspended = vec![];
+        for (callback_key, (token, controller)) in sub_controllers {
+            self.controller_groups.remove(&token);
+            if self.suspended.remove(&token) {
+                suspended.push(token);
+                continue;
+            }
+            poll_manager.remove(callback_key, &*controller.borrow())?;
+            if let Some(callback_key) = self.motion_callbacks.remove(&token) {
+                if let Some(motion_device) = controller.borrow().motion_device() {
+                    poll_manager.remove(callback_key, motion_device)?;
+                }
+            }
+
+            collected.push((token, controller));
+        }
+
+        Ok((collected, suspended))
+    }
+
+    /// Stop relaying a disconnected controller but keep its group, releasing everything it held
+    /// on the virtual controller. Return the group.
+    pub fn suspend_controller(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<ControllerManager, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<usize> {
+        let &group = self
+            .controller_groups
+            .get(&token)
+            .ok_or_else(|| anyhow::anyhow!("Token {token} is not in any combined group"))?;
+        let (_, virtual_controller, sub_controllers) =
+            self.groups.get(&group).ok_or_else(|| {
+                anyhow::anyhow!("Failed to get combined group info for group {group}")
+            })?;
+        let (id, (callback_key, (_, controller))) = sub_controllers
+            .iter()
+            .enumerate()
+            .find(|(_, (_, (t, _)))| *t == token)
+            .ok_or_else(|| anyhow::anyhow!("Token {token} is not in group {group}"))?;
+
+        poll_manager.remove(*callback_key, &*controller.borrow())?;
+        if let Some(callback_key) = self.motion_callbacks.remove(&token) {
+            if let Some(motion_device) = controller.borrow().motion_device() {
+                poll_manager.remove(callback_key, motion_device)?;
+            }
+        }
+        // Buttons held when the controller is gone must not count towards the unpair gesture.
+        controller.borrow_mut().reset_buttons_state();
+        virtual_controller
+            .borrow_mut()
+            .release_physical_device(id)?;
+        self.suspended.insert(token);
+        self.release_unpair_gesture(group, poll_manager)?;
+
+        Ok(group)
+    }
+
+    /// Put a reconnected controller back in place of a suspended one. Return the group.
+    pub fn resume_controller(
+        &mut self,
+        suspended_token: usize,
+        (token, controller): TokenController,
+        poll_manager: &mut PollManager<ControllerManager, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<usize> {
+        if !self.suspended.contains(&suspended_token) {
+            Err(anyhow::anyhow!("Token {suspended_token} is not suspended"))?;
+        }
+        let &group = self
+            .controller_groups
+            .get(&suspended_token)
+            .ok_or_else(|| {
+                anyhow::anyhow!("Token {suspended_token} is not in any combined group")
+            })?;
+        let (_, virtual_controller, sub_controllers) =
+            self.groups.get(&group).ok_or_else(|| {
+                anyhow::anyhow!("Failed to get combined group info for group {group}")
+            })?;
+        let id = sub_controllers
+            .iter()
+            .position(|(_, (t, _))| *t == suspended_token)
+            .ok_or_else(|| anyhow::anyhow!("Token {suspended_token} is not in group {group}"))?;
+        let virtual_controller = virtual_controller.clone();
+
+        virtual_controller
+            .borrow_mut()
+            .replace_physical_device(id, controller.clone());
+        let callback_key = self.subscribe_controller(
+            group,
+            id,
+            (token, controller.clone()),
+            &virtual_controller,
+            poll_manager,
+        )?;
+        if let Some((_, _, sub_controllers)) = self.groups.get_mut(&group) {
+            sub_controllers[id] = (callback_key, (token, controller.clone()));
+        }
+        self.suspended.remove(&suspended_token);
+        self.controller_groups.remove(&suspended_token);
+        self.controller_groups.insert(token, group);
+        if let Some(&player) = self.group_players.get(&group) {
+            show_player(&controller, player);
+        }
+
+        Ok(group)
+    }
+
+    /// Relay the motion data of a controller whose IMU device is linked after its group formed,
+    /// if the virtual controller has a motion device.
+    pub fn attach_motion_device(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<ControllerManager, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        if self.suspended.contains(&token) || self.motion_callbacks.contains_key(&token) {
+            return Ok(());
+        }
+        let group = self
+            .controller_groups
+            .get(&token)
+            .ok_or_else(|| anyhow::anyhow!("Token {token} is not in any combined group"))?;
+        let (_, virtual_controller, sub_controllers) = self.groups.get(group).ok_or_else(|| {
+            anyhow::anyhow!("Failed to get combined group info for group {group}")
+        })?;
+        let (id, (_, (_, controller))) = sub_controllers
+            .iter()
+            .enumerate()
+            .find(|(_, (_, (t, _)))| *t == token)
+            .ok_or_else(|| anyhow::anyhow!("Token {token} is not in group {group}"))?;
+
+        let callback_key =
+            subscribe_motion_device(id, controller, virtual_controller, poll_manager)?;
+        if let Some(callback_key) = callback_key {
+            self.motion_callbacks.insert(token, callback_key);
+        }
+
+        Ok(())
+    }
+
+    /// Relay the input events and the motion data of the `id`th controller of a group. Return the
+    /// callback key of the input events.
+    fn subscribe_controller(
+        &mut self,
+        group: usize,
+        id: usize,
+        (token, controller): TokenController,
+        virtual_controller: &Rc<RefCell<VirtualController>>,
+        poll_manager: &mut PollManager<ControllerManager, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<usize> {
+        let callback = Box::new({
+            let virtual_controller = virtual_controller.clone();
+            move |_ctx: &mut ControllerManager| match virtual_controller
+                .borrow_mut()
+                .relay_input_events(id)?
+            {
+                Some(GestureChange::Held(hold)) => {
+                    Ok(ControllerMessage::UnpairGestureHeld(group, hold))
+                }
+                Some(GestureChange::Released) => {
+                    Ok(ControllerMessage::UnpairGestureReleased(group))
+                }
+                None => Ok(ControllerMessage::Relay),
+            }
+        });
+
+        let callback_key = poll_manager.subscribe(
+            &*controller.borrow(),
+            polling::Event::readable(0),
+            polling::PollMode::Level,
+            callback,
+        )?;
+
+        if let Some(motion_callback_key) =
+            subscribe_motion_device(id, &controller, virtual_controller, poll_manager)?
+        {
+            self.motion_callbacks.insert(token, motion_callback_key);
+        }
+
+        Ok(callback_key)
+    }
+
+    /// Dissolve a group once its unpair gesture is held for `hold`, unless it is released before.
+    pub fn hold_unpair_gesture(
+        &mut self,
+        group: usize,
+        hold: Duration,
+        poll_manager: &mut PollManager<ControllerManager, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        if !self.groups.contains_key(&group) || self.gesture_timers.contains_key(&group) {
+            return Ok(());
+        }
+        let timer = poll_manager.add_timer(
+            hold,
+            None,
+            Box::new(move |_ctx: &mut ControllerManager| {
+                Ok(ControllerMessage::UnpairGesture(group))
+            }),
+        )?;
+        self.gesture_timers.insert(group, timer);
+
+        Ok(())
+    }
+
+    /// Stop the unpair gesture of a group from dissolving it.
+    pub fn release_unpair_gesture(
+        &mut self,
+        group: usize,
+        poll_manager: &mut PollManager<ControllerManager, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<()> {
+        if let Some(timer) = self.gesture_timers.remove(&group) {
+            poll_manager.remove_timer(timer)?;
+        }
+
+        Ok(())
+    }
+
+    /// The timer dissolving a group whose unpair gesture is held.
+    pub fn unpair_gesture_timer(&self, group: usize) -> Option<usize> {
+        self.gesture_timers.get(&group).copied()
+    }
+
+    /// Replace the key map of a group.
+    pub fn set_key_map(&mut self, group: usize, key_map_name: &str, config: &Config) -> Anyhow<()> {
+        let (_, virtual_controller, _) = self
+            .groups
+            .get(&group)
+            .ok_or_else(|| anyhow::anyhow!("No combined group {group}"))?;
+        virtual_controller
+            .borrow_mut()
+            .set_key_map(config.key_map(key_map_name)?)?;
+        self.group_key_maps.insert(group, key_map_name.to_string());
+
+        Ok(())
+    }
+
+    /// Change where the force feedback effects of a group go.
+    pub fn set_rumble_routing(&mut self, group: usize, routing: RumbleRouting) -> Anyhow<()> {
+        let (_, virtual_controller, _) = self
+            .groups
+            .get(&group)
+            .ok_or_else(|| anyhow::anyhow!("No combined group {group}"))?;
+        virtual_controller.borrow_mut().set_rumble_routing(routing);
+
+        Ok(())
+    }
+
+    /// Change the strength of the force feedback effects of a group.
+    pub fn set_rumble_intensity(&mut self, group: usize, intensity: f64) -> Anyhow<()> {
+        let (_, virtual_controller, _) = self
+            .groups
+            .get(&group)
+            .ok_or_else(|| anyhow::anyhow!("No combined group {group}"))?;
+        virtual_controller
+            .borrow_mut()
+            .set_rumble_intensity(intensity);
+
+        Ok(())
+    }
+
+    /// The player number of a group, starting from 1.
+    pub fn player(&self, group: usize) -> Option<usize> {
+        self.group_players.get(&group).map(|player| player + 1)
+    }
+
+    pub fn groups(&self) -> Vec<GroupSummary> {
+        let mut groups: Vec<_> = self
+            .groups
+            .iter()
+            .map(
+                |(&group, (_, virtual_controller, sub_controllers))| GroupSummary {
+                    group,
+                    player: self.player(group),
+                    key_map: self.group_key_maps.get(&group).cloned().unwrap_or_default(),
+                    rumble_routing: virtual_controller.borrow().rumble_routing(),
+                    controllers: sub_controllers
+                        .iter()
+                        .map(|(_, token_controller)| token_controller.clone())
+                        .collect(),
+                },
+            )
+            .collect();
+        groups.sort_by_key(|summary| summary.group);
+        groups
+    }
+
+    pub fn get_controller(&self, token: usize) -> Option<Rc<RefCell<Controller>>> {
+        let group = self.controller_groups.get(&token)?;
+        let (_, _, sub_controllers) = self.groups.get(group)?;
+        sub_controllers
+            .iter()
+            .find(|(_, (t, _))| *t == token)
+            .map(|(_, (_, controller))| controller.clone())
+    }
+
+    /// Stop relaying the motion data of a controller and detach its IMU device.
+    pub fn detach_motion_device(
+        &mut self,
+        token: usize,
+        poll_manager: &mut PollManager<ControllerManager, Anyhow<ControllerMessage>>,
+    ) -> Anyhow<Option<MotionDevice>> {
+        let group = self
+            .controller_groups
+            .get(&token)
+            .ok_or_else(|| anyhow::anyhow!("Token {token} is not in any combined group"))?;
+        let (_, virtual_controller, sub_controllers) = self.groups.get(group).ok_or_else(|| {
+            anyhow::anyhow!("Failed to get combined group info for group {group}")
+        })?;
+        let (id, (_, (_, controller))) = sub_controllers
+            .iter()
+            .enumerate()
+            .find(|(_, (_, (t, _)))| *t == token)
+            .ok_or_else(|| anyhow::anyhow!("Token {token} is not in group {group}"))?;
+
+        if let Some(callback_key) = self.motion_callbacks.remove(&token) {
+            if let Some(motion_device) = controller.borrow().motion_device() {
+                poll_manager.remove(callback_key, motion_device)?;
+            }
+        }
+        virtual_controller.borrow_mut().reset_motion_state(id);
+
+        Ok(controller.borrow_mut().detach_motion_device())
+    }
+}
+
+/// Relay the motion data of the `id`th controller of a virtual controller, if both of them have
+/// a motion device.
+fn subscribe_motion_device(
+    id: usize,
+    controller: &Rc<RefCell<Controller>>,
+    virtual_controller: &Rc<RefCell<VirtualController>>,
+    poll_manager: &mut PollManager<ControllerManager, Anyhow<ControllerMessage>>,
+) -> Anyhow<Option<usize>> {
+    let controller = controller.borrow();
+    let Some(motion_device) = controller.motion_device() else {
+        return Ok(None);
+    };
+    if !virtual_controller.borrow().has_motion_device() {
+        return Ok(None);
+    }
+
+    let callback = Box::new({
+        let virtual_controller = virtual_controller.clone();
+        move |_ctx: &mut ControllerManager| {
+            virtual_controller.borrow_mut().relay_motion_events(id)?;
+            Ok(ControllerMessage::Relay)
+        }
+    });
+    let callback_key = poll_manager.subscribe(
+        motion_device,
+        polling::Event::readable(0),
+        polling::PollMode::Level,
+        callback,
+    )?;
+
+    Ok(Some(callback_key))
+}
+
+/// Light the player LEDs of a controller with a 0-based player number.
+fn show_player(controller: &Rc<RefCell<Controller>>, player: usize) {
+    if let Some(Err(e)) = controller
+        .borrow()
+        .leds()
+        .map(|leds| leds.set_player(player + 1))
+    {
+        eprintln!("{e}");
+    }
 }
@@ -1,36 +1,117 @@
 #![allow(unused)]
 
-use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::{
+    collections::HashSet,
+    os::fd::{AsFd, AsRawFd, BorrowedFd},
+    path::Path,
+    rc::Rc,
+    time::Duration,
+};
 
 use anyhow::Result as Anyhow;
-use evdev::{Device, FetchEventsSynced, InputEvent};
+use evdev::{
+    AbsoluteAxisType, Device, FFEffect, FFEffectData, FFEffectKind, FFReplay, FFTrigger,
+    FetchEventsSynced, InputEvent,
+};
+use serde::Deserialize;
+
+use super::{
+    model_registry::{Model, ModelRegistry},
+    player_leds::PlayerLeds,
+};
+
+const FEEDBACK_RUMBLE_MAGNITUDE: u16 = 0x8000;
 
-// TODO: Support motion device.
 pub struct Controller {
     device: Device,
+    motion_device: Option<MotionDevice>,
+    leds: Option<PlayerLeds>,
+    /// The rumble effect played as feedback. It is erased when dropped, so it is kept until the
+    /// next one.
+    feedback_effect: Option<FFEffect>,
     buttons_state: ButtonsState,
-    model: Model,
+    model: Rc<Model>,
 }
 
 impl Controller {
-    pub fn new(device: udev::Device) -> Anyhow<Self> {
+    pub fn new(
+        device: udev::Device,
+        model_registry: &ModelRegistry,
+        sysfs_root: &Path,
+    ) -> Anyhow<Self> {
         let devname = device
             .devnode()
             .ok_or_else(|| anyhow::anyhow!("Failed to get devnode"))?;
         let _devpath = device.devpath();
+        // Generic devices have no player LEDs.
+        let leds = device
+            .parent_with_subsystem("hid")?
+            .map(|hid| PlayerLeds::new(sysfs_root, &hid.sysname().to_string_lossy()))
+            .filter(PlayerLeds::exists);
 
         let device = Device::open(devname)?;
-        let product_id = device.input_id().product();
-        let model = Model::from_product_id(product_id)?;
+        let model = model_registry.find_device(&device)?;
         let buttons_state = ButtonsState::default();
 
         Ok(Self {
             device,
+            motion_device: None,
+            leds,
+            feedback_effect: None,
             buttons_state,
             model,
         })
     }
 
+    /// Link the IMU device of the controller.
+    pub fn attach_motion_device(&mut self, motion_device: MotionDevice) {
+        self.motion_device = Some(motion_device);
+    }
+
+    pub fn detach_motion_device(&mut self) -> Option<MotionDevice> {
+        self.motion_device.take()
+    }
+
+    pub fn leds(&self) -> Option<&PlayerLeds> {
+        self.leds.as_ref()
+    }
+
+    /// Rumble `pulses` times for `pulse`, pausing as long between two pulses, as feedback to the
+    /// user.
+    pub fn rumble(&mut self, pulses: u16, pulse: Duration) -> Anyhow<()> {
+        self.feedback_effect = None;
+        if pulses == 0 {
+            return Ok(());
+        }
+
+        let length = pulse.as_millis().min(u16::MAX as u128) as u16;
+        let mut effect = self.device.upload_ff_effect(FFEffectData {
+            direction: 0,
+            trigger: FFTrigger::default(),
+            // The delay comes before every repetition, the first one included.
+            replay: FFReplay {
+                length,
+                delay: if pulses > 1 { length } else { 0 },
+            },
+            kind: FFEffectKind::Rumble {
+                strong_magnitude: FEEDBACK_RUMBLE_MAGNITUDE,
+                weak_magnitude: FEEDBACK_RUMBLE_MAGNITUDE,
+            },
+        })?;
+        effect.play(pulses.into())?;
+        self.feedback_effect = Some(effect);
+
+        Ok(())
+    }
+
+    pub fn motion_device(&self) -> Option<&MotionDevice> {
+        self.motion_device.as_ref()
+    }
+
+    pub fn motion_device_mut(&mut self) -> Option<&mut MotionDevice> {
+        self.motion_device.as_mut()
+    }
+
     pub fn handle_pairing_events(&mut self) -> Anyhow<PairingState> {
         let events = self.device.fetch_events()?;
         for event in events {
@@ -41,34 +122,37 @@ impl Controller {
     }
 
     fn get_pairing_state(&self) -> PairingState {
-        match self.model {
-            Model::LeftJoycon => {
-                if self.buttons_state.l ^ self.buttons_state.zl != 0 {
-                    PairingState::Waiting(self.get_model())
-                } else if self.buttons_state.sl != 0 && self.buttons_state.sr != 0 {
-                    PairingState::Horizontal
-                } else if self.buttons_state.l != 0 && self.buttons_state.zl != 0 {
-                    PairingState::Lone
-                } else {
-                    PairingState::Pairing
-                }
-            }
-            Model::RightJoycon => {
-                if self.buttons_state.r ^ self.buttons_state.zr != 0 {
-                    PairingState::Waiting(self.get_model())
-                } else if self.buttons_state.sl != 0 && self.buttons_state.sr != 0 {
-                    PairingState::Horizontal
-                } else if self.buttons_state.r != 0 && self.buttons_state.zr != 0 {
-                    PairingState::Lone
-                } else {
-                    PairingState::Pairing
-                }
-            }
-        }
+        self.model.pairing_state(&self.buttons_state.held())
+    }
+
+    pub fn get_model(&self) -> Rc<Model> {
+        self.model.clone()
+    }
+
+    /// The unique identifier of the controller, its Bluetooth MAC address.
+    pub fn uniq(&self) -> Option<&str> {
+        self.device.unique_name().filter(|uniq| !uniq.is_empty())
+    }
+
+    /// Track the pairing-related buttons from an event read outside of pairing.
+    pub fn track_button_event(&mut self, event: InputEvent) {
+        self.buttons_state.handle_event(event, &self.model);
+    }
+
+    /// Forget the tracked button states, so that presses seen before do not count towards
+    /// pairing.
+    pub fn reset_buttons_state(&mut self) {
+        self.buttons_state = ButtonsState::default();
     }
 
-    pub fn get_model(&self) -> Model {
-        self.model
+    /// Whether all the given buttons the controller has are held. A controller having none of
+    /// them never holds them.
+    pub fn is_holding(&self, buttons: &[Button]) -> bool {
+        let mut owned = buttons
+            .iter()
+            .filter(|&&button| self.model.has_button(button))
+            .peekable();
+        owned.peek().is_some() && owned.all(|&button| self.buttons_state.is_held(button))
     }
 }
 
@@ -101,91 +185,100 @@ impl AsFd for Controller {
     }
 }
 
-/// To store the button state. This struct only stores pairing-related buttons' state.
-#[derive(Default)]
-pub struct ButtonsState {
-    l: i32,
-    r: i32,
-    zl: i32,
-    zr: i32,
-    sl: i32,
-    sr: i32,
+/// The IMU evdev device of a controller, reporting accelerometer and gyroscope data.
+pub struct MotionDevice {
+    device: Device,
 }
 
-impl ButtonsState {
-    fn handle_event(&mut self, ev: evdev::InputEvent, model: &Model) {
-        let ev_type = ev.event_type();
-        let code = ev.code();
-        let key = evdev::Key::new(code);
-        let val = ev.value();
+impl MotionDevice {
+    pub fn new(device: udev::Device) -> Anyhow<Self> {
+        let devname = device
+            .devnode()
+            .ok_or_else(|| anyhow::anyhow!("Failed to get devnode"))?;
+        let device = Device::open(devname)?;
 
-        if ev_type != evdev::EventType::KEY {
-            return;
-        }
+        Ok(Self { device })
+    }
+}
 
-        if let Some(state) = model.get_mut_key_state(self, key) {
-            *state = val;
-        }
+impl AsRef<Device> for MotionDevice {
+    fn as_ref(&self) -> &Device {
+        &self.device
     }
 }
 
-const LEFT_JOYCON_PRODUCT_ID: u16 = 0x2006;
-const RIGHT_JOYCON_PRODUCT_ID: u16 = 0x2007;
+impl AsMut<Device> for MotionDevice {
+    fn as_mut(&mut self) -> &mut Device {
+        &mut self.device
+    }
+}
 
-#[derive(Copy, Clone, Debug)]
-pub enum Model {
-    LeftJoycon,
-    RightJoycon,
+impl AsRawFd for MotionDevice {
+    fn as_raw_fd(&self) -> std::os::unix::prelude::RawFd {
+        self.device.as_raw_fd()
+    }
 }
 
-impl Model {
-    fn get_mut_key_state<'s>(
-        &self,
-        buttons_state: &'s mut ButtonsState,
-        key: evdev::Key,
-    ) -> Option<&'s mut i32> {
-        match self {
-            Model::LeftJoycon => match key {
-                evdev::Key::BTN_TL => Some(&mut buttons_state.l),
-                evdev::Key::BTN_TL2 => Some(&mut buttons_state.zl),
-                evdev::Key::BTN_TR => Some(&mut buttons_state.sl),
-                evdev::Key::BTN_TR2 => Some(&mut buttons_state.sr),
-                _ => None,
-            },
-            Model::RightJoycon => match key {
-                evdev::Key::BTN_TL => Some(&mut buttons_state.sl),
-                evdev::Key::BTN_TL2 => Some(&mut buttons_state.sr),
-                evdev::Key::BTN_TR => Some(&mut buttons_state.r),
-                evdev::Key::BTN_TR2 => Some(&mut buttons_state.zr),
-                _ => None,
-            },
-        }
+impl AsFd for MotionDevice {
+    fn as_fd(&self) -> std::os::unix::prelude::BorrowedFd<'_> {
+        let raw_fd = self.as_raw_fd();
+
+        // # Safety
+        //
+        // The fd will remain open until self drops.
+        unsafe { BorrowedFd::borrow_raw(raw_fd) }
     }
+}
 
-    pub fn from_product_id(product_id: u16) -> Anyhow<Self> {
-        match product_id {
-            LEFT_JOYCON_PRODUCT_ID => Ok(Self::LeftJoycon),
-            RIGHT_JOYCON_PRODUCT_ID => Ok(Self::RightJoycon),
-            _ => Err(anyhow::anyhow!(
-                "Failed to determine the model type for product id {}",
-                product_id
-            )),
-        }
+/// To store the button state. This struct only stores pairing-related buttons' state.
+#[derive(Default)]
+pub struct ButtonsState {
+    held: HashSet<Button>,
+}
+
+impl ButtonsState {
+    fn is_held(&self, button: Button) -> bool {
+        self.held.contains(&button)
     }
 
-    pub fn is_left(&self) -> bool {
-        matches!(self, Self::LeftJoycon)
+    fn held(&self) -> Vec<Button> {
+        self.held.iter().copied().collect()
     }
 
-    pub fn is_right(&self) -> bool {
-        matches!(self, Self::RightJoycon)
+    fn handle_event(&mut self, ev: evdev::InputEvent, model: &Model) {
+        if ev.event_type() != evdev::EventType::KEY {
+            return;
+        }
+
+        if let Some(button) = model.button(evdev::Key::new(ev.code())) {
+            if ev.value() != 0 {
+                self.held.insert(button);
+            } else {
+                self.held.remove(&button);
+            }
+        }
     }
 }
 
+/// The pairing-related buttons, named as printed on the joycons. Controllers with other layouts
+/// map their buttons to these names in the model registry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Button {
+    L,
+    R,
+    Zl,
+    Zr,
+    Sl,
+    Sr,
+    Plus,
+    Minus,
+}
+
 #[derive(Debug)]
 pub enum PairingState {
     Pairing,
-    Waiting(Model),
+    Waiting(Rc<Model>),
     Lone,
     Horizontal,
 }
@@ -0,0 +1,374 @@
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use anyhow::Result as Anyhow;
+use evdev::{AbsoluteAxisType, Device, Key};
+use serde::Deserialize;
+
+use super::controller::{Button, PairingState};
+
+/// The part a controller model plays in pairing.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// The left half of a combined controller.
+    Left,
+    /// The right half of a combined controller.
+    Right,
+    /// A full controller, always used alone.
+    Single,
+}
+
+/// The pairing state a gesture leads to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GestureAction {
+    Waiting,
+    Lone,
+    Horizontal,
+}
+
+/// What a device is recognized by.
+pub struct DeviceInfo<'a> {
+    pub vendor: u16,
+    pub product: u16,
+    pub name: &'a str,
+    pub uniq: &'a str,
+}
+
+/// Rules matching the devices of a model. A device matches if it matches every rule given.
+#[derive(Default)]
+pub struct DeviceMatch {
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    /// A part of the device name.
+    pub name: Option<String>,
+    /// The whole `uniq` of the device, usually its MAC address, regardless of case.
+    pub uniq: Option<String>,
+}
+
+impl DeviceMatch {
+    fn matches(&self, device: &DeviceInfo) -> bool {
+        self.vendor.is_none_or(|vendor| vendor == device.vendor)
+            && self.product.is_none_or(|product| product == device.product)
+            && self
+                .name
+                .as_ref()
+                .is_none_or(|name| device.name.contains(name.as_str()))
+            && self
+                .uniq
+                .as_ref()
+                .is_none_or(|uniq| device.uniq.eq_ignore_ascii_case(uniq))
+    }
+}
+
+/// A controller model, describing how its controllers are recognized and paired. Any evdev
+/// device can be described, not only the Nintendo controllers.
+pub struct Model {
+    pub id: String,
+    pub device_match: DeviceMatch,
+    pub role: Role,
+    /// The pairing buttons, by the key codes they report.
+    pub buttons: HashMap<Key, Button>,
+    /// Each gesture is performed when exactly its buttons are held.
+    pub gestures: Vec<(Vec<Button>, GestureAction)>,
+    /// Motion axes inverted to orient the IMU to the frame of a left joycon held vertically.
+    pub inverted_motion_axes: Vec<AbsoluteAxisType>,
+}
+
+impl Model {
+    /// The pairing button reported by a key code.
+    pub fn button(&self, key: Key) -> Option<Button> {
+        self.buttons.get(&key).copied()
+    }
+
+    pub fn has_button(&self, button: Button) -> bool {
+        self.buttons.values().any(|&b| b == button)
+    }
+
+    /// The pairing state of the gesture performed with the held buttons.
+    pub fn pairing_state(self: &Rc<Self>, held: &[Button]) -> PairingState {
+        let performed = self.gestures.iter().find(|(buttons, _)| {
+            buttons.len() == held.len() && buttons.iter().all(|button| held.contains(button))
+        });
+        match performed {
+            Some((_, GestureAction::Waiting)) => PairingState::Waiting(self.clone()),
+            Some((_, GestureAction::Lone)) => PairingState::Lone,
+            Some((_, GestureAction::Horizontal)) => PairingState::Horizontal,
+            None => PairingState::Pairing,
+        }
+    }
+
+    /// Whether the controllers of the model can be held horizontally, that is, the model has a
+    /// gesture for it.
+    pub fn can_be_horizontal(&self) -> bool {
+        self.gestures
+            .iter()
+            .any(|(_, action)| *action == GestureAction::Horizontal)
+    }
+
+    pub fn is_left(&self) -> bool {
+        self.role == Role::Left
+    }
+
+    pub fn is_right(&self) -> bool {
+        self.role == Role::Right
+    }
+
+    /// Orient a motion axis value to the frame of a left joycon held vertically.
+    pub fn orient_motion_axis(&self, axis: AbsoluteAxisType, value: i32) -> i32 {
+        if self.inverted_motion_axes.contains(&axis) {
+            value.saturating_neg()
+        } else {
+            value
+        }
+    }
+}
+
+impl fmt::Debug for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+/// The known controller models. The first model matching a device is used.
+pub struct ModelRegistry {
+    models: Vec<Rc<Model>>,
+}
+
+impl ModelRegistry {
+    pub fn new(models: Vec<Model>) -> Self {
+        Self {
+            models: models.into_iter().map(Rc::new).collect(),
+        }
+    }
+
+    /// Determine the model of an evdev device.
+    pub fn find_device(&self, device: &Device) -> Anyhow<Rc<Model>> {
+        let input_id = device.input_id();
+        self.find(&DeviceInfo {
+            vendor: input_id.vendor(),
+            product: input_id.product(),
+            name: device.name().unwrap_or_default(),
+            uniq: device.unique_name().unwrap_or_default(),
+        })
+    }
+
+    pub fn find(&self, device: &DeviceInfo) -> Anyhow<Rc<Model>> {
+        self.models
+            .iter()
+            .find(|model| model.device_match.matches(device))
+            .cloned()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Failed to determine the model of {} ({:04x}:{:04x})",
+                    device.name,
+                    device.vendor,
+                    device.product
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn registry() -> ModelRegistry {
+        Config::default().model_registry().unwrap()
+    }
+
+    fn device(product: u16, name: &str) -> DeviceInfo<'_> {
+        DeviceInfo {
+            vendor: 0x057e,
+            product,
+            name,
+            uniq: "",
+        }
+    }
+
+    #[test]
+    fn builtin_models() {
+        let registry = registry();
+        let find = |product, name| registry.find(&device(product, name)).unwrap().id.clone();
+        assert_eq!(find(0x2006, "Nintendo Switch Left Joy-Con"), "left-joycon");
+        assert_eq!(
+            find(0x2007, "Nintendo Switch Right Joy-Con"),
+            "right-joycon"
+        );
+        assert_eq!(
+            find(0x2006, "Nintendo Switch NES Controller (L)"),
+            "nes-left"
+        );
+        assert_eq!(
+            find(0x200e, "Nintendo Switch Right Joy-Con (Grip)"),
+            "grip-right-joycon"
+        );
+        assert_eq!(
+            find(0x2009, "Nintendo Switch Pro Controller"),
+            "pro-controller"
+        );
+        assert_eq!(find(0x201e, "Genesis Controller"), "genesis-controller");
+        assert!(registry.find(&device(0x200e, "Joy-Con (Grip)")).is_err());
+        assert!(registry.find(&device(0x2008, "")).is_err());
+    }
+
+    #[test]
+    fn models() {
+        let registry = registry();
+        let find = |product, name| registry.find(&device(product, name)).map(|m| m.id.clone());
+        assert_eq!(find(0x2009, "").unwrap(), "pro-controller");
+        assert!(find(0x2008, "").is_err());
+        assert_eq!(
+            find(0x200e, "Nintendo Switch Left Joy-Con (Grip)").unwrap(),
+            "grip-left-joycon"
+        );
+        assert_eq!(
+            find(0x200e, "Nintendo Switch Right Joy-Con (Grip)").unwrap(),
+            "grip-right-joycon"
+        );
+        assert!(find(0x200e, "Nintendo Switch Joy-Con (Grip)").is_err());
+    }
+
+    #[test]
+    fn pro_controller_buttons() {
+        let registry = registry();
+        let pro = registry.find(&device(0x2009, "")).unwrap();
+        assert!(!pro.is_left() && !pro.is_right());
+        assert!(pro.has_button(Button::L) && pro.has_button(Button::Zr));
+        assert!(!pro.has_button(Button::Sl));
+        assert_eq!(pro.button(Key::BTN_TR), Some(Button::R));
+        let grip = registry
+            .find(&device(0x200e, "Nintendo Switch Left Joy-Con (Grip)"))
+            .unwrap();
+        assert!(!grip.has_button(Button::Sr));
+    }
+
+    #[test]
+    fn gestures() {
+        let left = registry().find(&device(0x2006, "")).unwrap();
+        let state = |held: &[Button]| format!("{:?}", left.pairing_state(held));
+        assert_eq!(state(&[Button::L]), "Waiting(left-joycon)");
+        assert_eq!(state(&[Button::Zl, Button::L]), "Lone");
+        assert_eq!(state(&[Button::Sl, Button::Sr]), "Horizontal");
+        assert_eq!(state(&[Button::L, Button::Sl, Button::Sr]), "Pairing");
+        assert_eq!(state(&[]), "Pairing");
+        assert!(left.can_be_horizontal());
+
+        let pro = registry().find(&device(0x2009, "")).unwrap();
+        assert_eq!(format!("{:?}", pro.pairing_state(&[Button::L])), "Pairing");
+        assert_eq!(
+            format!("{:?}", pro.pairing_state(&[Button::L, Button::R])),
+            "Lone"
+        );
+        assert!(pro.has_button(Button::Zr) && !pro.has_button(Button::Sl));
+        assert!(!pro.is_left() && !pro.is_right() && !pro.can_be_horizontal());
+    }
+
+    #[test]
+    fn buttons_and_motion() {
+        let registry = registry();
+        let right = registry.find(&device(0x2007, "")).unwrap();
+        assert_eq!(right.button(Key::BTN_TL), Some(Button::Sl));
+        assert_eq!(right.button(Key::BTN_SOUTH), None);
+        assert_eq!(right.orient_motion_axis(AbsoluteAxisType::ABS_X, 100), 100);
+        assert_eq!(right.orient_motion_axis(AbsoluteAxisType::ABS_Y, 100), -100);
+        assert_eq!(
+            right.orient_motion_axis(AbsoluteAxisType::ABS_RZ, 100),
+            -100
+        );
+
+        let grip = registry
+            .find(&device(0x200e, "Nintendo Switch Left Joy-Con (Grip)"))
+            .unwrap();
+        assert!(grip.is_left() && !grip.has_button(Button::Sl));
+        assert!(!grip.can_be_horizontal());
+    }
+
+    #[test]
+    fn device_match() {
+        let rule = DeviceMatch {
+            name: Some("Pedals".to_string()),
+            uniq: Some("aa:bb:cc:dd:ee:ff".to_string()),
+            ..Default::default()
+        };
+        let info = |name, uniq| DeviceInfo {
+            vendor: 0x1234,
+            product: 0x5678,
+            name,
+            uniq,
+        };
+        assert!(rule.matches(&info("USB Pedals", "AA:BB:CC:DD:EE:FF")));
+        assert!(!rule.matches(&info("USB Pedals", "")));
+        assert!(!rule.matches(&info("Keyboard", "aa:bb:cc:dd:ee:ff")));
+        assert!(DeviceMatch::default().matches(&info("", "")));
+    }
+
+    #[test]
+    fn config_models() {
+        let config = Config::parse(
+            r#"
+            [[models]]
+            id = "clone-left"
+            vendor = 0x1234
+            product = 0x5678
+            role = "left"
+            buttons = { BTN_TL = "l", BTN_TL2 = "zl" }
+            gestures = [{ buttons = ["l"], action = "waiting" }]
+
+            [[models]]
+            id = "pro-controller"
+            vendor = 0x057e
+            product = 0x2009
+            role = "single"
+            buttons = { BTN_START = "plus" }
+            gestures = [{ buttons = ["plus"], action = "lone" }]
+            "#,
+        )
+        .unwrap();
+        let registry = config.model_registry().unwrap();
+        let find = |vendor, product, name| {
+            registry.find(&DeviceInfo {
+                vendor,
+                product,
+                name,
+                uniq: "",
+            })
+        };
+        assert!(find(0x1234, 0x5678, "").unwrap().is_left());
+        let pro = find(0x057e, 0x2009, "").unwrap();
+        assert!(pro.has_button(Button::Plus) && !pro.has_button(Button::L));
+    }
+
+    #[test]
+    fn generic_models() {
+        let config = Config::parse(
+            r#"
+            [[models]]
+            id = "pedals"
+            name = "Pedal Board"
+            role = "right"
+            buttons = { BTN_0 = "r" }
+            gestures = [{ buttons = ["r"], action = "waiting" }]
+            "#,
+        )
+        .unwrap();
+        let model = config
+            .model_registry()
+            .unwrap()
+            .find(&DeviceInfo {
+                vendor: 0x0c45,
+                product: 0x7403,
+                name: "USB Pedal Board",
+                uniq: "",
+            })
+            .unwrap();
+        assert_eq!(model.id, "pedals");
+    }
+}
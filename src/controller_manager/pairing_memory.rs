@@ -0,0 +1,168 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result as Anyhow};
+use serde::{Deserialize, Serialize};
+
+/// How the controllers of a group are used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PairingMode {
+    /// A left and a right joycon combined, the left one first.
+    Combined,
+    Lone,
+    Horizontal,
+}
+
+/// A remembered group, its controllers identified by their `uniq`s.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pairing {
+    pub mode: PairingMode,
+    pub controllers: Vec<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StateFile {
+    #[serde(default)]
+    pairings: Vec<Pairing>,
+}
+
+/// The pairings of the controllers, kept in a state file across restarts.
+pub struct PairingMemory {
+    path: PathBuf,
+    pairings: Vec<Pairing>,
+}
+
+impl PairingMemory {
+    /// Load the pairings from the state file. A missing file has no pairing.
+    pub fn load(path: &Path) -> Anyhow<Self> {
+        let pairings = if path.exists() {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the state file {}", path.display()))?;
+            toml::from_str::<StateFile>(&content)
+                .with_context(|| format!("Invalid state file {}", path.display()))?
+                .pairings
+        } else {
+            vec![]
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            pairings,
+        })
+    }
+
+    /// A memory with no pairing, saved to `path`.
+    pub fn empty(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            pairings: vec![],
+        }
+    }
+
+    /// Remember a pairing, replacing the ones sharing a controller with it.
+    pub fn remember(&mut self, pairing: Pairing) -> Anyhow<()> {
+        self.pairings.retain(|remembered| {
+            !remembered
+                .controllers
+                .iter()
+                .any(|uniq| pairing.controllers.contains(uniq))
+        });
+        self.pairings.push(pairing);
+        self.save()
+    }
+
+    /// Forget the pairing of a controller. Return whether it had one.
+    pub fn forget(&mut self, uniq: &str) -> Anyhow<bool> {
+        let count = self.pairings.len();
+        self.pairings
+            .retain(|pairing| !pairing.controllers.iter().any(|u| u == uniq));
+        if self.pairings.len() == count {
+            return Ok(false);
+        }
+
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn find(&self, uniq: &str) -> Option<&Pairing> {
+        self.pairings
+            .iter()
+            .find(|pairing| pairing.controllers.iter().any(|u| u == uniq))
+    }
+
+    /// Write the state file, replacing it at once so that it is never half written.
+    fn save(&self) -> Anyhow<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let content = toml::to_string(&StateFile {
+            pairings: self.pairings.clone(),
+        })?;
+        let temp_path = self.path.with_extension("tmp");
+        std::fs::write(&temp_path, content)
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        std::fs::rename(&temp_path, &self.path)
+            .with_context(|| format!("Failed to write the state file {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairing(mode: PairingMode, controllers: &[&str]) -> Pairing {
+        Pairing {
+            mode,
+            controllers: controllers.iter().map(|uniq| uniq.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn remember_and_forget() {
+        let dir = std::env::temp_dir().join(format!("joycombinerd-memory-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("state/pairings.toml");
+
+        let mut memory = PairingMemory::load(&path).unwrap();
+        assert!(memory.find("a").is_none());
+        memory
+            .remember(pairing(PairingMode::Combined, &["a", "b"]))
+            .unwrap();
+        memory.remember(pairing(PairingMode::Lone, &["c"])).unwrap();
+
+        let mut memory = PairingMemory::load(&path).unwrap();
+        assert_eq!(
+            memory.find("b"),
+            Some(&pairing(PairingMode::Combined, &["a", "b"]))
+        );
+
+        // Pairing a controller again replaces its old pairing.
+        memory
+            .remember(pairing(PairingMode::Horizontal, &["a"]))
+            .unwrap();
+        assert!(memory.find("b").is_none());
+        assert_eq!(memory.find("a").unwrap().mode, PairingMode::Horizontal);
+
+        assert!(memory.forget("c").unwrap());
+        assert!(!memory.forget("c").unwrap());
+        let memory = PairingMemory::load(&path).unwrap();
+        assert!(memory.find("c").is_none());
+        assert!(memory.find("a").is_some());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_state_file() {
+        let path = std::env::temp_dir().join(format!(
+            "joycombinerd-invalid-state-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(&path, "pairings = 1").unwrap();
+        assert!(PairingMemory::load(&path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::VecDeque;
+
+use crate::config::PairingMatching;
+
+/// The side of a joycon waiting for a partner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// The controllers waiting for a partner of the other side, in arrival order.
+pub struct PairingQueue {
+    matching: PairingMatching,
+    left: VecDeque<usize>,
+    right: VecDeque<usize>,
+}
+
+impl PairingQueue {
+    pub fn new(matching: PairingMatching) -> Self {
+        Self {
+            matching,
+            left: VecDeque::new(),
+            right: VecDeque::new(),
+        }
+    }
+
+    pub fn set_matching(&mut self, matching: PairingMatching) {
+        self.matching = matching;
+    }
+
+    /// Queue a controller, or match it with a controller of the other side right away. Return the
+    /// matched `(left, right)` tokens.
+    pub fn push(&mut self, token: usize, side: Side) -> Option<(usize, usize)> {
+        if self.contains(token) {
+            return None;
+        }
+
+        let others = match side {
+            Side::Left => &mut self.right,
+            Side::Right => &mut self.left,
+        };
+        // The queues are in arrival order, so the closest in time is the latest one.
+        let other = match self.matching {
+            PairingMatching::Arrival => others.pop_front(),
+            PairingMatching::Proximity => others.pop_back(),
+        };
+
+        match (other, side) {
+            (Some(other), Side::Left) => Some((token, other)),
+            (Some(other), Side::Right) => Some((other, token)),
+            (None, Side::Left) => {
+                self.left.push_back(token);
+                None
+            }
+            (None, Side::Right) => {
+                self.right.push_back(token);
+                None
+            }
+        }
+    }
+
+    /// Remove a controller from the queue. Return whether it was queued.
+    pub fn remove(&mut self, token: usize) -> bool {
+        let count = self.left.len() + self.right.len();
+        self.left.retain(|&t| t != token);
+        self.right.retain(|&t| t != token);
+        self.left.len() + self.right.len() != count
+    }
+
+    pub fn contains(&self, token: usize) -> bool {
+        self.left.iter().chain(&self.right).any(|&t| t == token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_by_arrival() {
+        let mut queue = PairingQueue::new(PairingMatching::Arrival);
+        assert_eq!(queue.push(1, Side::Left), None);
+        assert_eq!(queue.push(2, Side::Left), None);
+        assert_eq!(queue.push(1, Side::Left), None);
+        assert_eq!(queue.push(3, Side::Right), Some((1, 3)));
+        assert_eq!(queue.push(4, Side::Right), Some((2, 4)));
+        assert!(!queue.contains(1));
+        assert_eq!(queue.push(5, Side::Right), None);
+        assert_eq!(queue.push(6, Side::Left), Some((6, 5)));
+    }
+
+    #[test]
+    fn match_by_proximity() {
+        let mut queue = PairingQueue::new(PairingMatching::Proximity);
+        queue.push(1, Side::Right);
+        queue.push(2, Side::Right);
+        assert_eq!(queue.push(3, Side::Left), Some((3, 2)));
+        assert_eq!(queue.push(4, Side::Left), Some((4, 1)));
+    }
+
+    #[test]
+    fn remove() {
+        let mut queue = PairingQueue::new(PairingMatching::Arrival);
+        queue.push(1, Side::Left);
+        queue.push(2, Side::Left);
+        assert!(queue.remove(1));
+        assert!(!queue.remove(1));
+        assert_eq!(queue.push(3, Side::Right), Some((2, 3)));
+    }
+}
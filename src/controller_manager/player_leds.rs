@@ -0,0 +1,181 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result as Anyhow};
+
+/// Number of player LEDs on a controller.
+const PLAYER_LED_COUNT: usize = 4;
+
+/// LED patterns for each player number, the same as the ones used by hid-nintendo.
+const PLAYER_LED_PATTERNS: [u8; 8] = [0x1, 0x3, 0x7, 0xf, 0x9, 0xa, 0xb, 0x6];
+
+pub const MAX_PLAYERS: usize = PLAYER_LED_PATTERNS.len();
+
+const BLINK_DELAY_MS: u32 = 500;
+const FAST_BLINK_DELAY_MS: u32 = 100;
+
+/// The player LEDs of a controller, driven through the LED class entries under the `leds/`
+/// directory of its HID device in sysfs.
+pub struct PlayerLeds {
+    leds_dir: PathBuf,
+}
+
+impl PlayerLeds {
+    /// `hid_name` is the sysname of the HID device, e.g. `0005:057E:2006.0001`.
+    pub fn new(sysfs_root: &Path, hid_name: &str) -> Self {
+        Self {
+            leds_dir: sysfs_root
+                .join("bus/hid/devices")
+                .join(hid_name)
+                .join("leds"),
+        }
+    }
+
+    /// Whether the controller has player LEDs.
+    pub fn exists(&self) -> bool {
+        self.led_dir(0).is_ok()
+    }
+
+    /// Light the LEDs with the pattern of a player number, starting from 1.
+    pub fn set_player(&self, player: usize) -> Anyhow<()> {
+        let pattern = player
+            .checked_sub(1)
+            .and_then(|i| PLAYER_LED_PATTERNS.get(i))
+            .ok_or_else(|| anyhow::anyhow!("Invalid player number {player}"))?;
+
+        for i in 0..PLAYER_LED_COUNT {
+            let led = self.led_dir(i)?;
+            write_attribute(&led, "trigger", "none")?;
+            write_attribute(&led, "brightness", &((pattern >> i) & 1).to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Blink all the LEDs with the timer trigger.
+    pub fn blink(&self) -> Anyhow<()> {
+        self.blink_with_delay(BLINK_DELAY_MS)
+    }
+
+    /// Blink all the LEDs faster than [`PlayerLeds::blink`].
+    pub fn blink_fast(&self) -> Anyhow<()> {
+        self.blink_with_delay(FAST_BLINK_DELAY_MS)
+    }
+
+    fn blink_with_delay(&self, delay_ms: u32) -> Anyhow<()> {
+        for i in 0..PLAYER_LED_COUNT {
+            let led = self.led_dir(i)?;
+            write_attribute(&led, "trigger", "timer")?;
+            write_attribute(&led, "delay_on", &delay_ms.to_string())?;
+            write_attribute(&led, "delay_off", &delay_ms.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Find the LED class entry of the `i`th player LED, named like
+    /// `0005:057E:2006.0001:green:player-1`.
+    fn led_dir(&self, i: usize) -> Anyhow<PathBuf> {
+        let suffix = format!(":player-{}", i + 1);
+        std::fs::read_dir(&self.leds_dir)
+            .with_context(|| format!("Failed to read {}", self.leds_dir.display()))?
+            .flatten()
+            .find(|entry| entry.file_name().to_string_lossy().ends_with(&suffix))
+            .map(|entry| entry.path())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Cannot find player LED {} in {}",
+                    i + 1,
+                    self.leds_dir.display()
+                )
+            })
+    }
+}
+
+fn write_attribute(led: &Path, attribute: &str, value: &str) -> Anyhow<()> {
+    let path = led.join(attribute);
+    std::fs::write(&path, value).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HID_NAME: &str = "0005:057E:2006.0001";
+
+    fn fake_sysfs(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("joycombinerd-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for i in 1..=PLAYER_LED_COUNT {
+            let led = root
+                .join("bus/hid/devices")
+                .join(HID_NAME)
+                .join("leds")
+                .join(format!("{HID_NAME}:green:player-{i}"));
+            std::fs::create_dir_all(&led).unwrap();
+            std::fs::write(led.join("brightness"), "0").unwrap();
+            std::fs::write(led.join("trigger"), "none").unwrap();
+        }
+        root
+    }
+
+    fn read(root: &Path, i: usize, attribute: &str) -> String {
+        std::fs::read_to_string(
+            root.join("bus/hid/devices")
+                .join(HID_NAME)
+                .join("leds")
+                .join(format!("{HID_NAME}:green:player-{i}"))
+                .join(attribute),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn set_player() {
+        let root = fake_sysfs("set-player");
+        let leds = PlayerLeds::new(&root, HID_NAME);
+
+        leds.set_player(3).unwrap();
+        let brightness: Vec<_> = (1..=4).map(|i| read(&root, i, "brightness")).collect();
+        assert_eq!(brightness, ["1", "1", "1", "0"]);
+
+        leds.set_player(6).unwrap();
+        let brightness: Vec<_> = (1..=4).map(|i| read(&root, i, "brightness")).collect();
+        assert_eq!(brightness, ["0", "1", "0", "1"]);
+
+        assert!(leds.set_player(0).is_err());
+        assert!(leds.set_player(MAX_PLAYERS + 1).is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn blink() {
+        let root = fake_sysfs("blink");
+        let leds = PlayerLeds::new(&root, HID_NAME);
+
+        leds.blink().unwrap();
+        for i in 1..=4 {
+            assert_eq!(read(&root, i, "trigger"), "timer");
+            assert_eq!(read(&root, i, "delay_on"), "500");
+            assert_eq!(read(&root, i, "delay_off"), "500");
+        }
+
+        leds.blink_fast().unwrap();
+        for i in 1..=4 {
+            assert_eq!(read(&root, i, "delay_on"), "100");
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn missing_leds() {
+        let root = fake_sysfs("missing");
+        assert!(PlayerLeds::new(&root, HID_NAME).exists());
+        let leds = PlayerLeds::new(&root, "0005:057E:2007.0002");
+        assert!(!leds.exists());
+        assert!(leds.set_player(1).is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,18 +1,27 @@
 use std::{
     cell::RefCell,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     os::fd::{AsFd, AsRawFd, BorrowedFd},
     rc::Rc,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result as Anyhow};
 use evdev::{
-    uinput::{FFUploadEvent, UInputEvent, VirtualDevice},
-    AbsInfo, AttributeSet, EventType, FFEffect, FFEffectData, FFEffectType, InputEvent, Key,
-    UInputEventType, UinputAbsSetup,
+    uinput::{UInputEvent, VirtualDevice},
+    AbsInfo, AbsoluteAxisType, AttributeSet, EventType, FFEffect, FFEffectData, FFEffectType,
+    InputEvent, InputEventKind, Key, MiscType, PropType, Synchronization, UInputEventType,
+    UinputAbsSetup,
 };
 
-use super::controller::Controller;
+use super::controller::{Button, Controller};
+use crate::config::Config;
+use capabilities::{Capabilities, DeviceCapabilities};
+use ff_engine::FFEngine;
+use rumble::Half;
+pub use rumble::RumbleRouting;
+use stick::StickProcessor;
+pub use stick::{DeadzoneShape, ResponseCurve, StickSide};
 
 pub trait KeyMap {
     fn map_key(
@@ -25,21 +34,97 @@ pub trait KeyMap {
 }
 
 const ABSINFO_VALUE: i32 = 0;
-const ABSINFO_MIN: i32 = -32767;
-const ABSINFO_MAX: i32 = 32767;
-const ABSINFO_FUZZ: i32 = 250;
-const ABSINFO_FLAT: i32 = 500;
-const ABSINFO_RESOLUTION: i32 = 0;
+
+/// The number of force feedback effects a virtual controller holds at once, as many as the
+/// memoryless force feedback devices of the kernel.
+const FF_EFFECTS_MAX: u32 = 16;
+
+/// Accelerometer axes followed by gyroscope axes, as reported by the joycon IMUs.
+const MOTION_AXES: [AbsoluteAxisType; 6] = [
+    AbsoluteAxisType::ABS_X,
+    AbsoluteAxisType::ABS_Y,
+    AbsoluteAxisType::ABS_Z,
+    AbsoluteAxisType::ABS_RX,
+    AbsoluteAxisType::ABS_RY,
+    AbsoluteAxisType::ABS_RZ,
+];
 
 pub struct VirtualController {
     virtual_device: VirtualDevice,
+    keys: AttributeSet<Key>,
+    axes: Vec<AbsoluteAxisType>,
+    motion_device: Option<VirtualMotionDevice>,
     physical_devices: Vec<Rc<RefCell<Controller>>>,
+    /// What the physical devices support, to check the key maps swapped in.
+    physical_capabilities: Vec<DeviceCapabilities>,
+    /// The keys and axes each physical device holds away from their neutral state, as
+    /// `(event type, code)`.
+    held_inputs: Vec<HashSet<(u16, u16)>>,
+    /// The stick processing of each physical device, applied before the key map.
+    sticks: Vec<StickProcessor>,
     key_map: Box<dyn KeyMap>,
-    rumble_effects: HashMap<u16, (Option<FFEffect>, Option<FFEffect>)>,
+    /// The force feedback effects uploaded to the virtual controller, by the effect ids allocated
+    /// by the virtual controller.
+    ff_effects: HashMap<u16, VirtualEffect>,
+    ff_engine: FFEngine,
+    rumble_routing: RumbleRouting,
+    /// Scale of the strength of every effect.
+    rumble_intensity: f64,
+    unpair_gesture: Option<UnpairGesture>,
+}
+
+/// A force feedback effect of a virtual controller and its uploads to the physical devices.
+struct VirtualEffect {
+    data: FFEffectData,
+    /// One upload per physical device, `None` where the device refused the effect.
+    uploads: Vec<Option<FFEffect>>,
+}
+
+impl VirtualEffect {
+    /// Play the uploads `count` times, or stop them if `count` is 0.
+    fn play(&mut self, count: i32) {
+        for (physical_device_id, upload) in self.uploads.iter_mut().enumerate() {
+            if let Some(Err(e)) = upload.as_mut().map(|ff| ff.play(count)) {
+                eprintln!(
+                    "Failed to forward the rumble data to physical device {physical_device_id}: {e}"
+                );
+            }
+        }
+    }
+}
+
+/// A chord held on every physical device of a virtual controller to dissolve it.
+struct UnpairGesture {
+    buttons: Vec<Button>,
+    hold: Duration,
+    held: bool,
+}
+
+/// A change of the unpair gesture of a virtual controller.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GestureChange {
+    /// The chord is held on every physical device. It dissolves the group once held for the
+    /// duration.
+    Held(Duration),
+    Released,
+}
+
+/// The companion motion device of a virtual controller. It reports the average of the motion
+/// data of the physical devices, each oriented to the same frame. The IMUs report at the same rate
+/// but with their own clocks, so only the reports of a reference IMU are relayed, with its
+/// timestamps, and the other IMUs contribute their latest data.
+struct VirtualMotionDevice {
+    virtual_device: VirtualDevice,
+    motion_states: Vec<Option<[i32; MOTION_AXES.len()]>>,
 }
 
 impl VirtualController {
-    pub fn relay_input_events(&mut self, physical_device_id: usize) -> Anyhow<()> {
+    /// Relay the input events of a physical device. Return the change of the unpair gesture, if
+    /// any.
+    pub fn relay_input_events(
+        &mut self,
+        physical_device_id: usize,
+    ) -> Anyhow<Option<GestureChange>> {
         let mut physical_device = self
             .physical_devices
             .get(physical_device_id)
@@ -49,9 +134,19 @@ impl VirtualController {
                 )
             })?
             .borrow_mut();
-        let events = physical_device.as_mut().fetch_events()?;
+        let events: Vec<InputEvent> = physical_device.as_mut().fetch_events()?.collect();
+        for &event in &events {
+            physical_device.track_button_event(event);
+        }
+        drop(physical_device);
 
+        let sticks = &mut self.sticks[physical_device_id];
+        let events: Vec<InputEvent> = events
+            .into_iter()
+            .flat_map(|event| sticks.process(event))
+            .collect();
         let relay_events: Vec<InputEvent> = events
+            .into_iter()
             .flat_map(|event| {
                 let original_code = event.code();
                 let original_type = event.event_type();
@@ -67,111 +162,328 @@ impl VirtualController {
             .map(|(event_type, code, value)| InputEvent::new_now(event_type, code, value))
             .collect();
 
+        let held_inputs = &mut self.held_inputs[physical_device_id];
+        for event in &relay_events {
+            if let Some(neutral) = neutral_value(event.event_type()) {
+                let input = (event.event_type().0, event.code());
+                if event.value() == neutral {
+                    held_inputs.remove(&input);
+                } else {
+                    held_inputs.insert(input);
+                }
+            }
+        }
+
         self.virtual_device.emit(&relay_events)?;
+
+        Ok(self.check_unpair_gesture())
+    }
+
+    fn check_unpair_gesture(&mut self) -> Option<GestureChange> {
+        let gesture = self.unpair_gesture.as_mut()?;
+        let held = self
+            .physical_devices
+            .iter()
+            .all(|device| device.borrow().is_holding(&gesture.buttons));
+        if held == gesture.held {
+            return None;
+        }
+
+        gesture.held = held;
+        Some(if held {
+            GestureChange::Held(gesture.hold)
+        } else {
+            GestureChange::Released
+        })
+    }
+
+    pub fn relay_motion_events(&mut self, physical_device_id: usize) -> Anyhow<()> {
+        let motion_device = self
+            .motion_device
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("The virtual device has no motion device"))?;
+        let mut physical_device = self
+            .physical_devices
+            .get(physical_device_id)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Failed to find physical device {physical_device_id} in the virtual device"
+                )
+            })?
+            .borrow_mut();
+        let model = physical_device.get_model();
+        let events: Vec<InputEvent> = physical_device
+            .motion_device_mut()
+            .ok_or_else(|| {
+                anyhow::anyhow!("Physical device {physical_device_id} has no motion device")
+            })?
+            .as_mut()
+            .fetch_events()?
+            .collect();
+
+        let mut timestamp = None;
+        for event in events {
+            match event.kind() {
+                InputEventKind::AbsAxis(axis) => {
+                    if let Some(i) = MOTION_AXES.iter().position(|&a| a == axis) {
+                        let state = motion_device.motion_states[physical_device_id]
+                            .get_or_insert([0; MOTION_AXES.len()]);
+                        state[i] = model.orient_motion_axis(axis, event.value());
+                    }
+                }
+                InputEventKind::Misc(MiscType::MSC_TIMESTAMP) => timestamp = Some(event.value()),
+                InputEventKind::Synchronization(Synchronization::SYN_REPORT) => {
+                    let timestamp = timestamp.take();
+                    if motion_device.reference() == Some(physical_device_id) {
+                        motion_device.emit(timestamp)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn has_motion_device(&self) -> bool {
+        self.motion_device.is_some()
+    }
+
+    /// Release the keys and center the axes held by a physical device which is gone, and forget
+    /// its motion data.
+    pub fn release_physical_device(&mut self, physical_device_id: usize) -> Anyhow<()> {
+        let held_inputs = self
+            .held_inputs
+            .get_mut(physical_device_id)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Failed to find physical device {physical_device_id} in the virtual device"
+                )
+            })?;
+        let events: Vec<InputEvent> = held_inputs
+            .drain()
+            .flat_map(|(event_type, code)| {
+                let event_type = EventType(event_type);
+                neutral_value(event_type)
+                    .map(|neutral| InputEvent::new_now(event_type, code, neutral))
+            })
+            .collect();
+        self.virtual_device.emit(&events)?;
+        self.reset_motion_state(physical_device_id);
+        // The buttons of the device are released with it.
+        if let Some(gesture) = self.unpair_gesture.as_mut() {
+            gesture.held = false;
+        }
+
+        Ok(())
+    }
+
+    /// Put a reconnected controller in place of a physical device.
+    pub fn replace_physical_device(
+        &mut self,
+        physical_device_id: usize,
+        controller: Rc<RefCell<Controller>>,
+    ) {
+        if let Some(physical_device) = self.physical_devices.get_mut(physical_device_id) {
+            *physical_device = controller;
+        }
+        if let Some(sticks) = self.sticks.get_mut(physical_device_id) {
+            sticks.recenter();
+        }
+        self.reset_motion_state(physical_device_id);
+
+        // The effects of the previous controller are gone with it.
+        let effects: Vec<_> = self
+            .ff_effects
+            .iter()
+            .map(|(&id, effect)| (id, effect.data))
+            .collect();
+        for (id, data) in effects {
+            let data = self.physical_effect(id, data, physical_device_id);
+            let Some(upload) = self
+                .ff_effects
+                .get_mut(&id)
+                .and_then(|effect| effect.uploads.get_mut(physical_device_id))
+            else {
+                continue;
+            };
+            *upload = None;
+            if let Err(e) =
+                upload_ff_effect(&self.physical_devices[physical_device_id], upload, data)
+            {
+                eprintln!(
+                    "Failed to upload the ff effect {id} to physical device {physical_device_id}: {e}"
+                );
+            }
+        }
+    }
+
+    /// Release all the keys and center all the axes, so that nothing stays held.
+    pub fn reset_state(&mut self) -> Anyhow<()> {
+        for held_inputs in &mut self.held_inputs {
+            held_inputs.clear();
+        }
+        let events: Vec<InputEvent> = self
+            .keys
+            .iter()
+            .map(|key| InputEvent::new_now(EventType::KEY, key.code(), 0))
+            .chain(
+                self.axes
+                    .iter()
+                    .map(|axis| InputEvent::new_now(EventType::ABSOLUTE, axis.0, ABSINFO_VALUE)),
+            )
+            .collect();
+        self.virtual_device.emit(&events)?;
+
         Ok(())
     }
 
+    /// Swap the key map. Everything held under the old key map is released first, since the new
+    /// one may never release it. The keys and axes of the virtual controller are registered when
+    /// it is created, so a key map reaching other ones is refused rather than losing their input.
+    pub fn set_key_map(&mut self, key_map: Box<dyn KeyMap>) -> Anyhow<()> {
+        let capabilities = Capabilities::derive(&self.physical_capabilities, &*key_map);
+        let (keys, axes) = capabilities.unregistered(&self.keys, &self.axes);
+        if !keys.is_empty() || !axes.is_empty() {
+            Err(anyhow::anyhow!(
+                "The key map reaches {keys:?} {axes:?}, which the virtual controller was not \
+                 created with. Form the group again to use it"
+            ))?;
+        }
+
+        self.reset_state()?;
+        self.key_map = key_map;
+
+        Ok(())
+    }
+
+    pub fn rumble_routing(&self) -> RumbleRouting {
+        self.rumble_routing
+    }
+
+    /// Route the force feedback effects anew, updating the effects already uploaded.
+    pub fn set_rumble_routing(&mut self, routing: RumbleRouting) {
+        self.rumble_routing = routing;
+        self.reroute_ff_effects();
+    }
+
+    /// Scale the strength of the force feedback effects anew, updating the effects already
+    /// uploaded.
+    pub fn set_rumble_intensity(&mut self, intensity: f64) {
+        self.rumble_intensity = intensity;
+        self.reroute_ff_effects();
+    }
+
+    pub fn ff_engine(&self) -> &FFEngine {
+        &self.ff_engine
+    }
+
+    /// Sample the emulated force feedback effects playing, updating the rumble of the physical
+    /// devices.
+    pub fn run_ff_engine(&mut self) -> Anyhow<()> {
+        let changes = self.ff_engine.tick(Instant::now(), |id| {
+            self.ff_effects.get(&id).map(|effect| effect.data)
+        })?;
+        for (id, magnitude) in changes {
+            let Some(effect) = self.ff_effects.get_mut(&id) else {
+                continue;
+            };
+            match magnitude {
+                Some(_) => {
+                    let data = effect.data;
+                    if let Err(e) = self.upload_ff_effect(id, data) {
+                        eprintln!("Failed to play the ff effect {id}: {e}");
+                    }
+                }
+                None => effect.play(0),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Upload every force feedback effect again, after a change of their routing or strength.
+    fn reroute_ff_effects(&mut self) {
+        let effects: Vec<_> = self
+            .ff_effects
+            .iter()
+            .map(|(&id, effect)| (id, effect.data))
+            .collect();
+        for (id, data) in effects {
+            if let Err(e) = self.upload_ff_effect(id, data) {
+                eprintln!("Failed to route the ff effect {id}: {e}");
+            }
+        }
+    }
+
+    /// Forget the motion data of a physical device whose IMU is gone.
+    pub fn reset_motion_state(&mut self, physical_device_id: usize) {
+        if let Some(state) = self
+            .motion_device
+            .as_mut()
+            .and_then(|motion_device| motion_device.motion_states.get_mut(physical_device_id))
+        {
+            *state = None;
+        }
+    }
+
     pub fn relay_output_events(&mut self) -> Anyhow<()> {
-        // HACK: Only the first two physical devices will receive the rumble command.
         let events: Vec<_> = self.virtual_device.fetch_events()?.collect();
         for event in events {
             match event.event_type() {
                 EventType::FORCEFEEDBACK => {
                     let event = unsafe { get_input_event_from_uinput_event(event) };
-                    let (ff_l, ff_r) = self
-                        .rumble_effects
-                        .get(&event.code())
-                        .ok_or_else(|| anyhow::anyhow!("No corresponding effects"))?;
-
-                    #[allow(clippy::get_first)]
-                    if let Some((phys_dev, ff)) = self
-                        .physical_devices
-                        .get(0)
-                        .and_then(|dev| ff_l.as_ref().map(|ff| (dev, ff)))
-                    {
-                        let code = ff.id();
-                        let ff = InputEvent::new_now(event.event_type(), code, event.value());
-                        phys_dev
-                            .borrow_mut()
-                            .as_mut()
-                            .send_events(&[ff])
-                            .with_context(|| {
-                                "Failed to forward the rumble data to the left controller"
-                            })?;
+                    if event.code() == FFEffectType::FF_GAIN.0 {
+                        self.ff_engine
+                            .set_gain(event.value().clamp(0, u16::MAX as i32) as u16);
+                        self.reroute_ff_effects();
+                        continue;
                     }
-                    if let Some((phys_dev, ff)) = self
-                        .physical_devices
-                        .get(1)
-                        .and_then(|dev| ff_r.as_ref().map(|ff| (dev, ff)))
-                    {
-                        let code = ff.id();
-                        let ff = InputEvent::new_now(event.event_type(), code, event.value());
-                        phys_dev
-                            .borrow_mut()
-                            .as_mut()
-                            .send_events(&[ff])
-                            .with_context(|| {
-                                "Failed to forward the rumble data to the right controller"
-                            })?;
+
+                    let id = event.code();
+                    let effect = self
+                        .ff_effects
+                        .get_mut(&id)
+                        .ok_or_else(|| anyhow::anyhow!("No corresponding effects"))?;
+                    if FFEngine::emulates(&effect.data) {
+                        // The engine repeats the effect, the physical devices play it until
+                        // stopped. It is sampled before playing, so that the physical devices do
+                        // not start at the magnitude of its previous playback.
+                        self.ff_engine.play(id, event.value(), Instant::now())?;
+                        self.run_ff_engine()?;
+                        if let Some(effect) = self.ff_effects.get_mut(&id) {
+                            effect.play(event.value().min(1));
+                        }
+                    } else {
+                        effect.play(event.value());
                     }
                 }
 
-                EventType::UINPUT => {
-                    match UInputEventType(event.code()) {
-                        UInputEventType::UI_FF_UPLOAD => {
-                            let mut upload = self
-                                .virtual_device
-                                .process_ff_upload(event)
-                                .with_context(|| "Failed to process the ff upload")?;
-                            let effect = upload.effect();
-                            let id = upload.effect_id();
-
-                            let allocate_new_effect =
-                                id == -1 || !self.rumble_effects.contains_key(&(id as u16));
-
-                            if allocate_new_effect {
-                                #[allow(clippy::get_first)]
-                                let effect_l = if let Some(device) = self.physical_devices.get(0) {
-                                    Some(upload_ff_effect(
-                                        &mut (**device).borrow_mut(),
-                                        effect,
-                                        &mut upload,
-                                    )?)
-                                } else {
-                                    None
-                                };
-
-                                let effect_r = if let Some(device) = self.physical_devices.get(1) {
-                                    Some(upload_ff_effect(
-                                        &mut (**device).borrow_mut(),
-                                        effect,
-                                        &mut upload,
-                                    )?)
-                                } else {
-                                    None
-                                };
-
-                                // HACK: use the id from left controller.
-                                let id = effect_l.as_ref().ok_or_else(|| anyhow::anyhow!("Virtual Controller don't even have at least one physical device!? 🤯"))?.id();
-                                self.rumble_effects.insert(id, (effect_l, effect_r));
-                                upload.set_effect_id(id as i16);
-                            }
+                EventType::UINPUT => match UInputEventType(event.code()) {
+                    UInputEventType::UI_FF_UPLOAD => {
+                        let mut upload = self
+                            .virtual_device
+                            .process_ff_upload(event)
+                            .with_context(|| "Failed to process the ff upload")?;
+                        let id = upload.effect_id() as u16;
+                        if let Err(e) = self.upload_ff_effect(id, upload.effect()) {
+                            upload.set_retval(-e.raw_os_error().unwrap_or(nix::libc::EIO));
+                            eprintln!("Failed to upload the ff effect {id}: {e}");
                         }
-                        UInputEventType::UI_FF_ERASE => {
-                            let mut erase = self
-                                .virtual_device
-                                .process_ff_erase(event)
-                                .with_context(|| "Failed to process the ff erase")?;
-                            let id = erase.effect_id() as u16;
-                            if self.rumble_effects.contains_key(&id) {
-                                self.rumble_effects.remove(&id);
-                            } else {
-                                erase.set_retval(-1);
-                            }
+                    }
+                    UInputEventType::UI_FF_ERASE => {
+                        let mut erase = self
+                            .virtual_device
+                            .process_ff_erase(event)
+                            .with_context(|| "Failed to process the ff erase")?;
+                        let id = erase.effect_id() as u16;
+                        self.ff_engine.stop(id)?;
+                        if self.ff_effects.remove(&id).is_none() {
+                            erase.set_retval(-nix::libc::EINVAL);
                         }
-                        _ => Err(anyhow::anyhow!("Unhandled uinput event {event:?}"))?,
                     }
-                }
+                    _ => Err(anyhow::anyhow!("Unhandled uinput event {event:?}"))?,
+                },
 
                 _ => Err(anyhow::anyhow!("Unhandled event: {event:?}"))?,
             }
@@ -180,97 +492,261 @@ impl VirtualController {
         Ok(())
     }
 
+    /// Upload a force feedback effect to the physical devices it is routed to, or update it where
+    /// it is already uploaded. The virtual effect fails only if every physical device refuses it;
+    /// the devices refusing it are left out when it plays.
+    fn upload_ff_effect(&mut self, id: u16, data: FFEffectData) -> std::io::Result<()> {
+        let physical_effects: Vec<_> = (0..self.physical_devices.len())
+            .map(|physical_device_id| self.physical_effect(id, data, physical_device_id))
+            .collect();
+        let is_new = !self.ff_effects.contains_key(&id);
+        let effect = self.ff_effects.entry(id).or_insert_with(|| VirtualEffect {
+            data,
+            uploads: self.physical_devices.iter().map(|_| None).collect(),
+        });
+        effect.data = data;
+
+        let mut error = None;
+        for (physical_device_id, ((controller, upload), data)) in self
+            .physical_devices
+            .iter()
+            .zip(&mut effect.uploads)
+            .zip(physical_effects)
+            .enumerate()
+        {
+            if let Err(e) = upload_ff_effect(controller, upload, data) {
+                eprintln!(
+                    "Failed to upload the ff effect {id} to physical device {physical_device_id}: {e}"
+                );
+                error = Some(e);
+            }
+        }
+
+        let uploaded = effect.uploads.iter().any(Option::is_some);
+        let (keep, res) = upload_outcome(error, uploaded, is_new);
+        if !keep {
+            self.ff_effects.remove(&id);
+        }
+
+        res
+    }
+
+    /// The effect a physical device plays for an effect of the virtual controller: the effect
+    /// itself, or a rumble at the sampled magnitude if it is emulated. It is routed, then scaled by
+    /// the intensity and the gain.
+    fn physical_effect(
+        &self,
+        id: u16,
+        data: FFEffectData,
+        physical_device_id: usize,
+    ) -> Option<FFEffectData> {
+        let data = if FFEngine::emulates(&data) {
+            ff_engine::rumble(data, self.ff_engine.magnitude(id))
+        } else {
+            data
+        };
+        let half = Half::of(physical_device_id, self.physical_devices.len());
+        let strength = self.rumble_intensity * self.ff_engine.gain() as f64 / u16::MAX as f64;
+        rumble::route(data, self.rumble_routing, half, strength)
+    }
+
     pub fn new(
         physical_devices: Vec<Rc<RefCell<Controller>>>,
         key_map: Box<dyn KeyMap>,
+        config: &Config,
     ) -> Anyhow<Self> {
-        // HACK: 0x2008 is an illegal product id for nintendo joycons, preventing re-registering
-        // the virtual controllers.
-        let input_id = evdev::InputId::new(evdev::BusType::BUS_VIRTUAL, 0x059e, 0x2008, 0x0000);
+        let device_config = &config.virtual_device;
+        let input_id = evdev::InputId::new(
+            evdev::BusType::BUS_VIRTUAL,
+            device_config.vendor,
+            device_config.product,
+            device_config.version,
+        );
         let mut virtual_device = evdev::uinput::VirtualDeviceBuilder::new()?
-            .name("Nintendo Switch Combined Joycons")
+            .name(&device_config.name)
             .input_id(input_id);
 
-        let mut keys = AttributeSet::new();
-        keys.insert(Key::BTN_SELECT);
-        keys.insert(Key::BTN_Z);
-        keys.insert(Key::BTN_THUMBL);
-        keys.insert(Key::BTN_START);
-        keys.insert(Key::BTN_MODE);
-        keys.insert(Key::BTN_THUMBR);
-        keys.insert(Key::BTN_SOUTH);
-        keys.insert(Key::BTN_EAST);
-        keys.insert(Key::BTN_NORTH);
-        keys.insert(Key::BTN_WEST);
-        keys.insert(Key::BTN_DPAD_UP);
-        keys.insert(Key::BTN_DPAD_DOWN);
-        keys.insert(Key::BTN_DPAD_LEFT);
-        keys.insert(Key::BTN_DPAD_RIGHT);
-        keys.insert(Key::BTN_TL);
-        keys.insert(Key::BTN_TR);
-        keys.insert(Key::BTN_TL2);
-        keys.insert(Key::BTN_TR2);
+        let physical_capabilities = physical_devices
+            .iter()
+            .map(|device| DeviceCapabilities::of(device.borrow().as_ref()))
+            .collect::<Anyhow<Vec<_>>>()?;
+        let mut capabilities = Capabilities::derive(&physical_capabilities, &*key_map);
+        capabilities.override_axes(&config.axis);
+        // The physical devices able to rumble play the emulated effects.
+        if capabilities.ff.contains(FFEffectType::FF_RUMBLE) {
+            for ff in ff_engine::EMULATED {
+                capabilities.ff.insert(ff);
+            }
+        }
+
+        let keys = capabilities.keys;
         virtual_device = virtual_device
             .with_keys(&keys)
             .with_context(|| "Failed to init keys for the virtual controller")?;
 
-        let absinfo = AbsInfo::new(
-            ABSINFO_VALUE,
-            ABSINFO_MIN,
-            ABSINFO_MAX,
-            ABSINFO_FUZZ,
-            ABSINFO_FLAT,
-            ABSINFO_RESOLUTION,
-        );
-        virtual_device = virtual_device
-            .with_absolute_axis(&UinputAbsSetup::new(
-                evdev::AbsoluteAxisType::ABS_X,
-                absinfo,
-            ))
-            .with_context(|| "Failed to init abs for the virtual controller")?;
-        virtual_device = virtual_device
-            .with_absolute_axis(&UinputAbsSetup::new(
-                evdev::AbsoluteAxisType::ABS_Y,
-                absinfo,
-            ))
-            .with_context(|| "Failed to init abs for the virtual controller")?;
-        virtual_device = virtual_device
-            .with_absolute_axis(&UinputAbsSetup::new(
-                evdev::AbsoluteAxisType::ABS_RX,
-                absinfo,
-            ))
-            .with_context(|| "Failed to init abs for the virtual controller")?;
-        virtual_device = virtual_device
-            .with_absolute_axis(&UinputAbsSetup::new(
-                evdev::AbsoluteAxisType::ABS_RY,
-                absinfo,
-            ))
-            .with_context(|| "Failed to init abs for the virtual controller")?;
-
-        let mut ff_effects = AttributeSet::new();
-        ff_effects.insert(FFEffectType::FF_RUMBLE);
-        ff_effects.insert(FFEffectType::FF_PERIODIC);
-        ff_effects.insert(FFEffectType::FF_SQUARE);
-        ff_effects.insert(FFEffectType::FF_TRIANGLE);
-        ff_effects.insert(FFEffectType::FF_SINE);
-        ff_effects.insert(FFEffectType::FF_GAIN);
-        virtual_device = virtual_device
-            .with_ff(&ff_effects)
-            .with_context(|| "Failed to init FF for the virtual controller")?;
+        let mut axes = vec![];
+        for (&code, range) in &capabilities.axes {
+            let axis = AbsoluteAxisType(code);
+            virtual_device = virtual_device
+                .with_absolute_axis(&UinputAbsSetup::new(axis, range.absinfo(ABSINFO_VALUE)))
+                .with_context(|| "Failed to init abs for the virtual controller")?;
+            axes.push(axis);
+        }
+
+        if capabilities.ff.iter().next().is_some() {
+            virtual_device = virtual_device
+                .with_ff(&capabilities.ff)
+                .with_context(|| "Failed to init FF for the virtual controller")?
+                .with_ff_effects_max(FF_EFFECTS_MAX);
+        }
 
         let virtual_device = virtual_device
             .build()
             .with_context(|| "Failed to create the virtual controller")?;
 
+        let motion_device = VirtualMotionDevice::new(&physical_devices, config)?;
+        let sticks = physical_devices
+            .iter()
+            .map(|device| StickProcessor::new(&device.borrow(), config))
+            .collect::<Anyhow<_>>()?;
+        // A controller used alone reports the buttons of the chord as its own, so only the
+        // groups of several controllers have the gesture.
+        let gesture_config = &config.unpair_gesture;
+        let unpair_gesture =
+            (gesture_config.enabled && physical_devices.len() > 1).then(|| UnpairGesture {
+                buttons: gesture_config.buttons.clone(),
+                hold: Duration::from_millis(gesture_config.hold_ms),
+                held: false,
+            });
+
         Ok(Self {
             virtual_device,
+            keys,
+            axes,
+            motion_device,
+            held_inputs: vec![HashSet::new(); physical_devices.len()],
             physical_devices,
+            physical_capabilities,
+            sticks,
             key_map,
-            rumble_effects: HashMap::new(),
+            ff_effects: HashMap::new(),
+            ff_engine: FFEngine::new()?,
+            rumble_routing: config.rumble.routing,
+            rumble_intensity: config.rumble.intensity,
+            unpair_gesture,
         })
     }
 }
 
+impl VirtualMotionDevice {
+    /// Create a motion device if any of the physical devices has an IMU.
+    fn new(physical_devices: &[Rc<RefCell<Controller>>], config: &Config) -> Anyhow<Option<Self>> {
+        let mut abs_state = None;
+        let mut motion_states = vec![];
+        for controller in physical_devices {
+            let controller = controller.borrow();
+            let motion_state = match controller.motion_device() {
+                Some(motion_device) => {
+                    let state = motion_device.as_ref().get_abs_state()?;
+                    let model = controller.get_model();
+                    let motion_state = MOTION_AXES
+                        .map(|axis| model.orient_motion_axis(axis, state[axis.0 as usize].value));
+                    abs_state.get_or_insert(state);
+                    Some(motion_state)
+                }
+                None => None,
+            };
+            motion_states.push(motion_state);
+        }
+
+        let Some(abs_state) = abs_state else {
+            return Ok(None);
+        };
+
+        let device_config = &config.virtual_device;
+        let input_id = evdev::InputId::new(
+            evdev::BusType::BUS_VIRTUAL,
+            device_config.vendor,
+            device_config.product,
+            device_config.version,
+        );
+        let name = format!("{} (IMU)", device_config.name);
+        let mut virtual_device = evdev::uinput::VirtualDeviceBuilder::new()?
+            .name(&name)
+            .input_id(input_id);
+
+        let mut properties = AttributeSet::new();
+        properties.insert(PropType::ACCELEROMETER);
+        virtual_device = virtual_device
+            .with_properties(&properties)
+            .with_context(|| "Failed to init properties for the virtual motion device")?;
+
+        for axis in MOTION_AXES {
+            let absinfo = abs_state[axis.0 as usize];
+            let absinfo = AbsInfo::new(
+                ABSINFO_VALUE,
+                absinfo.minimum,
+                absinfo.maximum,
+                absinfo.fuzz,
+                absinfo.flat,
+                absinfo.resolution,
+            );
+            virtual_device = virtual_device
+                .with_absolute_axis(&UinputAbsSetup::new(axis, absinfo))
+                .with_context(|| "Failed to init abs for the virtual motion device")?;
+        }
+
+        let mut misc = AttributeSet::new();
+        misc.insert(MiscType::MSC_TIMESTAMP);
+        virtual_device = virtual_device
+            .with_msc(&misc)
+            .with_context(|| "Failed to init msc for the virtual motion device")?;
+
+        let virtual_device = virtual_device
+            .build()
+            .with_context(|| "Failed to create the virtual motion device")?;
+
+        Ok(Some(Self {
+            virtual_device,
+            motion_states,
+        }))
+    }
+
+    /// The physical device whose IMU paces the reports: the first one with motion data, which is
+    /// the left half of a combined group while it is connected.
+    fn reference(&self) -> Option<usize> {
+        self.motion_states.iter().position(Option::is_some)
+    }
+
+    /// Emit the average motion data of the physical devices.
+    fn emit(&mut self, timestamp: Option<i32>) -> Anyhow<()> {
+        let states: Vec<_> = self.motion_states.iter().flatten().collect();
+        if states.is_empty() {
+            return Ok(());
+        }
+
+        let mut events: Vec<InputEvent> = MOTION_AXES
+            .iter()
+            .enumerate()
+            .map(|(i, axis)| {
+                let sum: i64 = states.iter().map(|state| state[i] as i64).sum();
+                let value = (sum / states.len() as i64) as i32;
+                InputEvent::new_now(EventType::ABSOLUTE, axis.0, value)
+            })
+            .collect();
+        if let Some(timestamp) = timestamp {
+            events.push(InputEvent::new_now(
+                EventType::MISC,
+                MiscType::MSC_TIMESTAMP.0,
+                timestamp,
+            ));
+        }
+
+        self.virtual_device.emit(&events)?;
+        Ok(())
+    }
+}
+
 impl AsRawFd for VirtualController {
     fn as_raw_fd(&self) -> std::os::unix::prelude::RawFd {
         self.virtual_device.as_raw_fd()
@@ -288,6 +764,15 @@ impl AsFd for VirtualController {
     }
 }
 
+/// The value of a key or an axis at rest.
+fn neutral_value(event_type: EventType) -> Option<i32> {
+    match event_type {
+        EventType::KEY => Some(0),
+        EventType::ABSOLUTE => Some(ABSINFO_VALUE),
+        _ => None,
+    }
+}
+
 /// I need to relay the input events the virtual devices received to the real devices. Anyway I can
 /// neither copy or clone the `UInputEvent` struct or get the inner `InputEvent` struct. So I will
 /// do some dirty work here. 🤓
@@ -301,48 +786,75 @@ unsafe fn get_input_event_from_uinput_event(event: UInputEvent) -> InputEvent {
     unsafe { *ptr.cast::<InputEvent>() }
 }
 
+/// Upload a force feedback effect to a physical device, updating its previous upload if any. A
+/// failed update drops the previous upload, so that outdated data is never played, and so does an
+/// effect routed away from the device.
 fn upload_ff_effect(
-    controller: &mut Controller,
-    effect: FFEffectData,
-    upload: &mut FFUploadEvent,
-) -> Anyhow<FFEffect> {
-    let res = controller.as_mut().upload_ff_effect(effect);
-
-    if let Err(e) = &res {
-        upload.set_retval(e.raw_os_error().unwrap_or(-1));
+    controller: &RefCell<Controller>,
+    upload: &mut Option<FFEffect>,
+    data: Option<FFEffectData>,
+) -> std::io::Result<()> {
+    let Some(data) = data else {
+        *upload = None;
+        return Ok(());
+    };
+    let res = match upload {
+        Some(effect) => effect.update(data),
+        None => controller
+            .borrow_mut()
+            .as_mut()
+            .upload_ff_effect(data)
+            .map(|effect| *upload = Some(effect)),
+    };
+
+    if res.is_err() {
+        *upload = None;
     }
 
-    res.with_context(|| "Failed to upload the ff effect")
+    res
 }
 
-pub mod key_map {
-    #![allow(unused)]
-    use super::KeyMap;
-
-    pub struct Id;
-
-    impl KeyMap for Id {
-        fn map_key(
-            &self,
-            _controller_id: usize,
-            event_type: evdev::EventType,
-            code: u16,
-            value: i32,
-        ) -> Option<(evdev::EventType, u16, i32)> {
-            Some((event_type, code, value))
-        }
+/// Whether to keep an effect after uploading it to the physical devices, and the result of the
+/// upload. It fails if a device refused the effect and none holds it, and a new effect is then
+/// forgotten.
+fn upload_outcome(
+    error: Option<std::io::Error>,
+    uploaded: bool,
+    is_new: bool,
+) -> (bool, std::io::Result<()>) {
+    match error {
+        Some(e) if !uploaded => (!is_new, Err(e)),
+        _ => (true, Ok(())),
     }
+}
 
-    impl Id {
-        pub fn new() -> Self {
-            Self
-        }
-    }
+mod capabilities;
+mod ff_engine;
+pub mod key_map;
+mod rumble;
+mod stick;
 
-    pub type LoneConstrollerKeyMap = Id;
-    pub type CombinedControllerKeyMap = Id;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    /// FIXME: Rotate the keymap for horizontal controllers.
-    pub type HorizontalLeftControllerKeyMap = Id;
-    pub type HorizontalRightControllerKeyMap = Id;
+    fn refused() -> Option<std::io::Error> {
+        Some(std::io::ErrorKind::OutOfMemory.into())
+    }
+
+    #[test]
+    fn partial_upload_failure() {
+        // Accepted by one device.
+        let (keep, res) = upload_outcome(refused(), true, true);
+        assert!(keep && res.is_ok());
+        // Refused by all the devices.
+        let (keep, res) = upload_outcome(refused(), false, true);
+        assert!(!keep && res.is_err());
+        // An update refused by all the devices keeps the effect for its id.
+        let (keep, res) = upload_outcome(refused(), false, false);
+        assert!(keep && res.is_err());
+        // Routed away from all the devices.
+        let (keep, res) = upload_outcome(None, false, true);
+        assert!(keep && res.is_ok());
+    }
 }
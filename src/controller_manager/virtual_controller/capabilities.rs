@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result as Anyhow;
+use evdev::{AbsInfo, AbsoluteAxisType, AttributeSet, Device, EventType, FFEffectType, Key};
+
+use super::KeyMap;
+use crate::config::AxisConfig;
+
+/// The keys, axes and force feedback effects a physical device supports.
+pub struct DeviceCapabilities {
+    pub keys: Vec<Key>,
+    pub axes: Vec<(AbsoluteAxisType, AbsInfo)>,
+    pub ff: Vec<FFEffectType>,
+}
+
+impl DeviceCapabilities {
+    pub fn of(device: &Device) -> Anyhow<Self> {
+        let keys = device
+            .supported_keys()
+            .map(|keys| keys.iter().collect())
+            .unwrap_or_default();
+        let axes = match device.supported_absolute_axes() {
+            Some(axes) => {
+                let state = device.get_abs_state()?;
+                axes.iter()
+                    .map(|axis| {
+                        let info = state[axis.0 as usize];
+                        let info = AbsInfo::new(
+                            info.value,
+                            info.minimum,
+                            info.maximum,
+                            info.fuzz,
+                            info.flat,
+                            info.resolution,
+                        );
+                        (axis, info)
+                    })
+                    .collect()
+            }
+            None => vec![],
+        };
+        let ff = device
+            .supported_ff()
+            .map(|ff| ff.iter().collect())
+            .unwrap_or_default();
+
+        Ok(Self { keys, axes, ff })
+    }
+}
+
+/// The parameters of an axis of the virtual controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AxisRange {
+    pub min: i32,
+    pub max: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    pub resolution: i32,
+}
+
+impl AxisRange {
+    fn merge(&mut self, other: AxisRange) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.fuzz = self.fuzz.max(other.fuzz);
+        self.flat = self.flat.max(other.flat);
+        self.resolution = self.resolution.max(other.resolution);
+    }
+
+    pub fn absinfo(&self, value: i32) -> AbsInfo {
+        AbsInfo::new(
+            value.clamp(self.min, self.max),
+            self.min,
+            self.max,
+            self.fuzz,
+            self.flat,
+            self.resolution,
+        )
+    }
+}
+
+/// The capabilities of a virtual controller: what its physical devices report, as seen through
+/// its key map.
+#[derive(Default)]
+pub struct Capabilities {
+    pub keys: AttributeSet<Key>,
+    pub axes: BTreeMap<u16, AxisRange>,
+    pub ff: AttributeSet<FFEffectType>,
+}
+
+impl Capabilities {
+    /// Pass every key and the bounds of every axis of the physical devices through the key map,
+    /// collecting the keys and axes it leads to. An axis takes the range of the values mapped to
+    /// it, and the fuzz, flat and resolution of the axes mapped to it. The force feedback effects
+    /// are the ones supported by any physical device.
+    pub fn derive(devices: &[DeviceCapabilities], key_map: &dyn KeyMap) -> Self {
+        let mut capabilities = Self::default();
+        for (id, device) in devices.iter().enumerate() {
+            for key in &device.keys {
+                for value in [0, 1] {
+                    let mapped = key_map.map_key(id, EventType::KEY, key.code(), value);
+                    capabilities.add(mapped, None);
+                }
+            }
+            for (axis, info) in &device.axes {
+                for value in [info.minimum(), info.maximum()] {
+                    let mapped = key_map.map_key(id, EventType::ABSOLUTE, axis.0, value);
+                    capabilities.add(mapped, Some(info));
+                }
+            }
+            for &ff in &device.ff {
+                capabilities.ff.insert(ff);
+            }
+        }
+
+        capabilities
+    }
+
+    fn add(&mut self, mapped: Option<(EventType, u16, i32)>, source: Option<&AbsInfo>) {
+        match mapped {
+            Some((EventType::KEY, code, _)) => self.keys.insert(Key::new(code)),
+            Some((EventType::ABSOLUTE, code, value)) => {
+                let range = AxisRange {
+                    min: value.min(0),
+                    max: value.max(0),
+                    fuzz: source.map_or(0, AbsInfo::fuzz),
+                    flat: source.map_or(0, AbsInfo::flat),
+                    resolution: source.map_or(0, AbsInfo::resolution),
+                };
+                self.axes
+                    .entry(code)
+                    .and_modify(|merged| merged.merge(range))
+                    .or_insert(range);
+            }
+            _ => {}
+        }
+    }
+
+    /// The keys and axes of these capabilities missing from the ones registered on a virtual
+    /// controller.
+    pub fn unregistered(
+        &self,
+        keys: &AttributeSet<Key>,
+        axes: &[AbsoluteAxisType],
+    ) -> (Vec<Key>, Vec<AbsoluteAxisType>) {
+        let missing_keys = self
+            .keys
+            .iter()
+            .filter(|&key| !keys.contains(key))
+            .collect();
+        let missing_axes = self
+            .axes
+            .keys()
+            .map(|&code| AbsoluteAxisType(code))
+            .filter(|axis| !axes.contains(axis))
+            .collect();
+
+        (missing_keys, missing_axes)
+    }
+
+    /// Replace the parameters of every axis with the ones set in the configuration.
+    pub fn override_axes(&mut self, config: &AxisConfig) {
+        for range in self.axes.values_mut() {
+            range.min = config.min.unwrap_or(range.min);
+            range.max = config.max.unwrap_or(range.max);
+            range.fuzz = config.fuzz.unwrap_or(range.fuzz);
+            range.flat = config.flat.unwrap_or(range.flat);
+            range.resolution = config.resolution.unwrap_or(range.resolution);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller_manager::key_map::{self, Rule, RuleKeyMap};
+
+    fn stick(axis: AbsoluteAxisType) -> (AbsoluteAxisType, AbsInfo) {
+        (axis, AbsInfo::new(0, -32767, 32767, 250, 500, 0))
+    }
+
+    fn joycons() -> Vec<DeviceCapabilities> {
+        vec![
+            DeviceCapabilities {
+                keys: vec![Key::BTN_DPAD_LEFT, Key::BTN_TL, Key::BTN_Z],
+                axes: vec![
+                    stick(AbsoluteAxisType::ABS_X),
+                    stick(AbsoluteAxisType::ABS_Y),
+                ],
+                ff: vec![FFEffectType::FF_RUMBLE],
+            },
+            DeviceCapabilities {
+                keys: vec![Key::BTN_SOUTH, Key::BTN_TR, Key::BTN_MODE],
+                axes: vec![(
+                    AbsoluteAxisType::ABS_RX,
+                    AbsInfo::new(0, -30000, 32000, 100, 1000, 10),
+                )],
+                ff: vec![FFEffectType::FF_RUMBLE, FFEffectType::FF_GAIN],
+            },
+        ]
+    }
+
+    #[test]
+    fn union_of_devices() {
+        let capabilities = Capabilities::derive(&joycons(), &*key_map::from_name("id").unwrap());
+        let keys: Vec<_> = capabilities.keys.iter().collect();
+        assert_eq!(keys.len(), 6);
+        assert!(capabilities.keys.contains(Key::BTN_Z));
+        assert!(capabilities.keys.contains(Key::BTN_MODE));
+        assert_eq!(
+            capabilities.axes[&AbsoluteAxisType::ABS_RX.0],
+            AxisRange {
+                min: -30000,
+                max: 32000,
+                fuzz: 100,
+                flat: 1000,
+                resolution: 10
+            }
+        );
+        assert_eq!(capabilities.axes.len(), 3);
+        assert!(capabilities.ff.contains(FFEffectType::FF_GAIN));
+        assert!(!capabilities.ff.contains(FFEffectType::FF_PERIODIC));
+    }
+
+    #[test]
+    fn through_key_map() {
+        let key_map = RuleKeyMap::new(
+            vec![
+                (
+                    None,
+                    Rule::KeyToAxis {
+                        from: Key::BTN_DPAD_LEFT,
+                        to: AbsoluteAxisType::ABS_HAT0X,
+                        value: -1,
+                    },
+                ),
+                (
+                    None,
+                    Rule::AxisToKey {
+                        from: AbsoluteAxisType::ABS_Y,
+                        to: Key::BTN_TL2,
+                        threshold: 16000,
+                    },
+                ),
+                (
+                    Some(1),
+                    Rule::KeyToKey {
+                        from: Key::BTN_SOUTH,
+                        to: Key::BTN_EAST,
+                    },
+                ),
+            ],
+            false,
+        );
+        let capabilities = Capabilities::derive(&joycons(), &key_map);
+        let keys: Vec<_> = capabilities.keys.iter().collect();
+        assert_eq!(keys, [Key::BTN_EAST, Key::BTN_TL2]);
+        let axes: Vec<_> = capabilities.axes.iter().map(|(&a, &r)| (a, r)).collect();
+        assert_eq!(
+            axes,
+            [(
+                AbsoluteAxisType::ABS_HAT0X.0,
+                AxisRange {
+                    min: -1,
+                    max: 0,
+                    fuzz: 0,
+                    flat: 0,
+                    resolution: 0
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn unregistered() {
+        let registered = Capabilities::derive(&joycons(), &*key_map::from_name("id").unwrap());
+        let axes: Vec<_> = registered
+            .axes
+            .keys()
+            .map(|&code| AbsoluteAxisType(code))
+            .collect();
+        let same = Capabilities::derive(&joycons(), &*key_map::from_name("id").unwrap());
+        assert_eq!(same.unregistered(&registered.keys, &axes), (vec![], vec![]));
+
+        let key_map = RuleKeyMap::new(
+            vec![(
+                None,
+                Rule::KeyToAxis {
+                    from: Key::BTN_Z,
+                    to: AbsoluteAxisType::ABS_HAT0X,
+                    value: 1,
+                },
+            )],
+            true,
+        );
+        let other = Capabilities::derive(&joycons(), &key_map);
+        assert_eq!(
+            other.unregistered(&registered.keys, &axes),
+            (vec![], vec![AbsoluteAxisType::ABS_HAT0X])
+        );
+    }
+
+    #[test]
+    fn override_axes() {
+        let mut capabilities =
+            Capabilities::derive(&joycons(), &*key_map::from_name("id").unwrap());
+        capabilities.override_axes(&AxisConfig {
+            flat: Some(0),
+            ..Default::default()
+        });
+        let rx = capabilities.axes[&AbsoluteAxisType::ABS_RX.0];
+        assert_eq!((rx.min, rx.fuzz, rx.flat), (-30000, 100, 0));
+    }
+}
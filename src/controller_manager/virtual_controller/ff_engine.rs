@@ -0,0 +1,423 @@
+use std::{
+    collections::HashMap,
+    f64::consts::PI,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result as Anyhow;
+use evdev::{FFEffectData, FFEffectKind, FFEffectType, FFEnvelope, FFReplay, FFWaveform};
+use nix::{
+    errno::Errno,
+    sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags},
+};
+
+/// How often the emulated effects are sampled. Waveforms faster than half of this rate are
+/// aliased.
+const TICK: Duration = Duration::from_millis(20);
+
+/// The force feedback types emulated for the physical devices supporting rumble.
+pub const EMULATED: [FFEffectType; 7] = [
+    FFEffectType::FF_PERIODIC,
+    FFEffectType::FF_SQUARE,
+    FFEffectType::FF_TRIANGLE,
+    FFEffectType::FF_SINE,
+    FFEffectType::FF_SAW_UP,
+    FFEffectType::FF_SAW_DOWN,
+    FFEffectType::FF_GAIN,
+];
+
+/// Plays the periodic effects in software, since the physical devices only rumble. While a
+/// periodic effect plays, its waveform, envelope and replay are sampled every tick into the
+/// magnitude of a rumble effect played by the physical devices.
+pub struct FFEngine {
+    timer: TimerFd,
+    /// The emulated effects playing, by virtual effect id.
+    playing: HashMap<u16, Playback>,
+    gain: u16,
+}
+
+struct Playback {
+    started: Instant,
+    count: i32,
+    /// The magnitude last sampled, to update the physical devices only on changes. `None` until
+    /// the first sample, which is always pushed: the physical devices may still hold the
+    /// magnitude of a previous playback.
+    magnitude: Option<u16>,
+}
+
+impl FFEngine {
+    pub fn new() -> Anyhow<Self> {
+        let timer = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+        )?;
+
+        Ok(Self {
+            timer,
+            playing: HashMap::new(),
+            gain: u16::MAX,
+        })
+    }
+
+    /// Whether an effect is played by the engine instead of the physical devices.
+    pub fn emulates(data: &FFEffectData) -> bool {
+        matches!(data.kind, FFEffectKind::Periodic { .. })
+    }
+
+    /// The gain set on the virtual controller, scaling every effect.
+    pub fn gain(&self) -> u16 {
+        self.gain
+    }
+
+    pub fn set_gain(&mut self, gain: u16) {
+        self.gain = gain;
+    }
+
+    /// The magnitude an emulated effect plays at, 0 if it is not playing.
+    pub fn magnitude(&self, id: u16) -> u16 {
+        self.playing
+            .get(&id)
+            .and_then(|playback| playback.magnitude)
+            .unwrap_or(0)
+    }
+
+    /// Play an emulated effect `count` times from `now`, or stop it if `count` is 0.
+    pub fn play(&mut self, id: u16, count: i32, now: Instant) -> Anyhow<()> {
+        if count <= 0 {
+            return self.stop(id);
+        }
+
+        self.playing.insert(
+            id,
+            Playback {
+                started: now,
+                count,
+                magnitude: None,
+            },
+        );
+        if self.playing.len() == 1 {
+            self.timer.set(
+                Expiration::Interval(TICK.into()),
+                TimerSetTimeFlags::empty(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn stop(&mut self, id: u16) -> Anyhow<()> {
+        if self.playing.remove(&id).is_some() && self.playing.is_empty() {
+            self.timer.unset()?;
+        }
+
+        Ok(())
+    }
+
+    /// Sample the playing effects at `now`, looking their data up with `effect`. Return the
+    /// effects whose magnitude changed, with `None` for the effects done playing.
+    pub fn tick(
+        &mut self,
+        now: Instant,
+        effect: impl Fn(u16) -> Option<FFEffectData>,
+    ) -> Anyhow<Vec<(u16, Option<u16>)>> {
+        // Consume the expirations, or the level-triggered timer keeps firing.
+        match self.timer.wait() {
+            Ok(()) | Err(Errno::EAGAIN) => {}
+            Err(e) => Err(e)?,
+        }
+
+        let mut changes = vec![];
+        for (&id, playback) in &mut self.playing {
+            let magnitude = effect(id).and_then(|data| {
+                sample(
+                    &data,
+                    now.saturating_duration_since(playback.started),
+                    playback.count,
+                )
+            });
+            match magnitude {
+                Some(magnitude) if Some(magnitude) == playback.magnitude => {}
+                Some(magnitude) => {
+                    playback.magnitude = Some(magnitude);
+                    changes.push((id, Some(magnitude)));
+                }
+                None => changes.push((id, None)),
+            }
+        }
+        for (id, _) in changes.iter().filter(|(_, magnitude)| magnitude.is_none()) {
+            self.stop(*id)?;
+        }
+
+        Ok(changes)
+    }
+}
+
+impl AsRawFd for FFEngine {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+impl AsFd for FFEngine {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // # Safety
+        //
+        // The timer fd will remain open until self drops.
+        unsafe { BorrowedFd::borrow_raw(self.as_raw_fd()) }
+    }
+}
+
+/// The rumble effect the physical devices play for an emulated effect sampled at `magnitude`. It
+/// lasts until stopped, the engine doing the timing.
+pub fn rumble(data: FFEffectData, magnitude: u16) -> FFEffectData {
+    FFEffectData {
+        replay: FFReplay {
+            length: 0,
+            delay: 0,
+        },
+        kind: FFEffectKind::Rumble {
+            strong_magnitude: magnitude,
+            weak_magnitude: magnitude,
+        },
+        ..data
+    }
+}
+
+/// The rumble magnitude of a periodic effect `elapsed` after it started playing `count` times, or
+/// `None` once it is done. Each repetition waits for the replay delay, then plays for the replay
+/// length, forever if the length is 0.
+fn sample(data: &FFEffectData, elapsed: Duration, count: i32) -> Option<u16> {
+    let FFEffectKind::Periodic {
+        waveform,
+        period,
+        magnitude,
+        offset,
+        phase,
+        envelope,
+    } = data.kind
+    else {
+        return None;
+    };
+
+    let delay = data.replay.delay as u128;
+    let length = data.replay.length as u128;
+    let elapsed = elapsed.as_millis();
+    let (repetition, elapsed) = match length {
+        0 => (0, elapsed),
+        _ => (elapsed / (delay + length), elapsed % (delay + length)),
+    };
+    if repetition >= count as u128 {
+        return None;
+    }
+    if elapsed < delay {
+        return Some(0);
+    }
+    let elapsed = elapsed - delay;
+
+    // The phase shifts the wave by a fraction of the period, 0x10000 being a whole period.
+    let position = match period {
+        0 => 0.25,
+        _ => (elapsed % period as u128) as f64 / period as f64 + phase as f64 / 65536.0,
+    };
+    let level = offset as f64
+        + apply_envelope(magnitude, &envelope, elapsed, length) * wave(waveform, position.fract());
+
+    // A motor cannot push back, so the negative half of the wave is silent. Scale the levels of
+    // 0x7fff at most to the magnitudes of 0xffff at most, as the kernel does.
+    Some((level.max(0.0) * 2.0).min(u16::MAX as f64) as u16)
+}
+
+/// The magnitude of an effect `elapsed` after it started playing: rising from the attack level
+/// during the attack, and falling to the fade level during the fade at the end of the replay.
+fn apply_envelope(magnitude: i16, envelope: &FFEnvelope, elapsed: u128, length: u128) -> f64 {
+    let peak = (magnitude as f64).abs();
+    let attack_length = envelope.attack_length as u128;
+    let fade_length = envelope.fade_length as u128;
+    let level = if elapsed < attack_length {
+        let attack_level = envelope.attack_level as f64;
+        attack_level + (peak - attack_level) * elapsed as f64 / attack_length as f64
+    } else if length > 0 && elapsed + fade_length > length {
+        let fade_level = envelope.fade_level as f64;
+        fade_level + (peak - fade_level) * (length - elapsed) as f64 / fade_length as f64
+    } else {
+        peak
+    };
+
+    level.copysign(magnitude as f64)
+}
+
+/// The value of a waveform, between -1 and 1, at a position between 0 and 1 in its period.
+fn wave(waveform: FFWaveform, position: f64) -> f64 {
+    match waveform {
+        FFWaveform::Sine => (2.0 * PI * position).sin(),
+        FFWaveform::Square if position < 0.5 => 1.0,
+        FFWaveform::Square => -1.0,
+        FFWaveform::Triangle => (2.0 * PI * position).sin().asin() * 2.0 / PI,
+        FFWaveform::SawUp => 2.0 * position - 1.0,
+        FFWaveform::SawDown => 1.0 - 2.0 * position,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::FFTrigger;
+
+    fn periodic(
+        waveform: FFWaveform,
+        length: u16,
+        delay: u16,
+        envelope: FFEnvelope,
+    ) -> FFEffectData {
+        FFEffectData {
+            direction: 0,
+            trigger: FFTrigger::default(),
+            replay: FFReplay { length, delay },
+            kind: FFEffectKind::Periodic {
+                waveform,
+                period: 100,
+                magnitude: 0x4000,
+                offset: 0,
+                phase: 0,
+                envelope,
+            },
+        }
+    }
+
+    fn no_envelope() -> FFEnvelope {
+        FFEnvelope {
+            attack_length: 0,
+            attack_level: 0,
+            fade_length: 0,
+            fade_level: 0,
+        }
+    }
+
+    fn at(data: &FFEffectData, ms: u64, count: i32) -> Option<u16> {
+        sample(data, Duration::from_millis(ms), count)
+    }
+
+    #[test]
+    fn waveforms() {
+        let sine = periodic(FFWaveform::Sine, 0, 0, no_envelope());
+        assert_eq!(at(&sine, 0, 1), Some(0));
+        assert_eq!(at(&sine, 25, 1), Some(0x8000));
+        assert_eq!(at(&sine, 75, 1), Some(0));
+        assert_eq!(at(&sine, 10_000, 1), Some(0));
+
+        let square = periodic(FFWaveform::Square, 0, 0, no_envelope());
+        assert_eq!(at(&square, 10, 1), Some(0x8000));
+        assert_eq!(at(&square, 60, 1), Some(0));
+
+        let saw = periodic(FFWaveform::SawUp, 0, 0, no_envelope());
+        assert_eq!(at(&saw, 0, 1), Some(0));
+        assert_eq!(at(&saw, 50, 1), Some(0));
+        assert_eq!(at(&saw, 75, 1), Some(0x4000));
+        let saw = periodic(FFWaveform::SawDown, 0, 0, no_envelope());
+        assert_eq!(at(&saw, 0, 1), Some(0x8000));
+        assert_eq!(at(&saw, 75, 1), Some(0));
+
+        let triangle = periodic(FFWaveform::Triangle, 0, 0, no_envelope());
+        assert_eq!(at(&triangle, 25, 1), Some(0x8000));
+        assert_eq!(at(&triangle, 50, 1), Some(0));
+        assert_eq!(at(&triangle, 75, 1), Some(0));
+    }
+
+    #[test]
+    fn replay() {
+        let square = periodic(FFWaveform::Square, 200, 100, no_envelope());
+        assert_eq!(at(&square, 50, 1), Some(0));
+        assert_eq!(at(&square, 110, 1), Some(0x8000));
+        assert_eq!(at(&square, 300, 1), None);
+        // The second repetition waits for the delay again.
+        assert_eq!(at(&square, 350, 2), Some(0));
+        assert_eq!(at(&square, 410, 2), Some(0x8000));
+        assert_eq!(at(&square, 600, 2), None);
+    }
+
+    #[test]
+    fn envelope() {
+        let envelope = FFEnvelope {
+            attack_length: 100,
+            attack_level: 0,
+            fade_length: 100,
+            fade_level: 0x2000,
+        };
+        let square = periodic(FFWaveform::Square, 1000, 0, envelope);
+        assert_eq!(at(&square, 0, 1), Some(0));
+        assert_eq!(at(&square, 25, 1), Some(0x2000));
+        assert_eq!(at(&square, 500, 1), Some(0x8000));
+        assert_eq!(at(&square, 925, 1), Some(0x7000));
+    }
+
+    #[test]
+    fn offset_and_rumble() {
+        let mut data = periodic(FFWaveform::Square, 0, 0, no_envelope());
+        if let FFEffectKind::Periodic { offset, .. } = &mut data.kind {
+            *offset = 0x7fff;
+        }
+        assert_eq!(at(&data, 10, 1), Some(u16::MAX));
+        assert!(FFEngine::emulates(&data));
+
+        let rumble = rumble(data, 1000);
+        assert!(!FFEngine::emulates(&rumble));
+        assert_eq!(rumble.replay.length, 0);
+        assert_eq!(
+            rumble.kind,
+            FFEffectKind::Rumble {
+                strong_magnitude: 1000,
+                weak_magnitude: 1000
+            }
+        );
+    }
+
+    #[test]
+    fn playback() {
+        let mut engine = FFEngine::new().unwrap();
+        let square = periodic(FFWaveform::Square, 100, 0, no_envelope());
+        let start = Instant::now();
+        engine.play(3, 1, start).unwrap();
+        assert_eq!(engine.magnitude(3), 0);
+
+        let changes = engine.tick(start, |_| Some(square)).unwrap();
+        assert_eq!(changes, [(3, Some(0x8000))]);
+        assert_eq!(engine.magnitude(3), 0x8000);
+        assert!(engine.tick(start, |_| Some(square)).unwrap().is_empty());
+
+        let changes = engine
+            .tick(start + Duration::from_millis(100), |_| Some(square))
+            .unwrap();
+        assert_eq!(changes, [(3, None)]);
+        assert_eq!(engine.magnitude(3), 0);
+
+        engine.play(3, 1, start).unwrap();
+        engine.play(3, 0, start).unwrap();
+        assert!(engine.tick(start, |_| Some(square)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn replay_after_finishing() {
+        let mut engine = FFEngine::new().unwrap();
+        let square = periodic(FFWaveform::Square, 100, 100, no_envelope());
+        let start = Instant::now();
+        let tick = |engine: &mut FFEngine, ms| {
+            engine
+                .tick(start + Duration::from_millis(ms), |_| Some(square))
+                .unwrap()
+        };
+        engine.play(3, 1, start).unwrap();
+        assert_eq!(tick(&mut engine, 110), [(3, Some(0x8000))]);
+        assert_eq!(tick(&mut engine, 200), [(3, None)]);
+
+        // The physical devices last played the effect at full strength, so the silence of the
+        // delay is pushed to them.
+        engine
+            .play(3, 1, start + Duration::from_millis(300))
+            .unwrap();
+        assert_eq!(tick(&mut engine, 300), [(3, Some(0))]);
+        assert_eq!(engine.magnitude(3), 0);
+        assert!(tick(&mut engine, 350).is_empty());
+        assert_eq!(tick(&mut engine, 410), [(3, Some(0x8000))]);
+    }
+}
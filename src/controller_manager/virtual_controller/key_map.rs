@@ -0,0 +1,341 @@
+#![allow(unused)]
+use anyhow::Result as Anyhow;
+use evdev::{AbsoluteAxisType, EventType, Key};
+
+use super::KeyMap;
+
+mod rule_key_map;
+
+pub use rule_key_map::{Code, Rule, RuleKeyMap};
+
+pub struct Id;
+
+impl KeyMap for Id {
+    fn map_key(
+        &self,
+        _controller_id: usize,
+        event_type: evdev::EventType,
+        code: u16,
+        value: i32,
+    ) -> Option<(evdev::EventType, u16, i32)> {
+        Some((event_type, code, value))
+    }
+}
+
+impl Id {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+pub type LoneConstrollerKeyMap = Id;
+
+pub const ID: &str = "id";
+pub const COMBINED: &str = "combined";
+pub const HORIZONTAL_LEFT: &str = "horizontal-left";
+pub const HORIZONTAL_RIGHT: &str = "horizontal-right";
+
+/// Build a built-in key map by its name.
+pub fn from_name(name: &str) -> Anyhow<Box<dyn KeyMap>> {
+    match name {
+        ID => Ok(Box::new(Id::new())),
+        COMBINED => Ok(Box::new(CombinedControllerKeyMap::new())),
+        HORIZONTAL_LEFT => Ok(Box::new(HorizontalLeftControllerKeyMap::new())),
+        HORIZONTAL_RIGHT => Ok(Box::new(HorizontalRightControllerKeyMap::new())),
+        _ => Err(anyhow::anyhow!("Unknown key map: {name}")),
+    }
+}
+
+/// Key map for a left and a right joycon combined, the left one first.
+///
+/// The SL/SR buttons of both joycons and the Capture button have no gamepad counterpart, so they
+/// are reported as extra buttons, like the back paddles of other controllers. Everything else is
+/// forwarded unchanged.
+pub struct CombinedControllerKeyMap;
+
+impl CombinedControllerKeyMap {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl KeyMap for CombinedControllerKeyMap {
+    fn map_key(
+        &self,
+        controller_id: usize,
+        event_type: EventType,
+        code: u16,
+        value: i32,
+    ) -> Option<(EventType, u16, i32)> {
+        if event_type != EventType::KEY {
+            return Some((event_type, code, value));
+        }
+
+        let key = match (controller_id, Key::new(code)) {
+            // SL & SR of the left joycon
+            (0, Key::BTN_TR) => Key::BTN_TRIGGER_HAPPY1,
+            (0, Key::BTN_TR2) => Key::BTN_TRIGGER_HAPPY2,
+            // SL & SR of the right joycon
+            (1, Key::BTN_TL) => Key::BTN_TRIGGER_HAPPY3,
+            (1, Key::BTN_TL2) => Key::BTN_TRIGGER_HAPPY4,
+            // Capture
+            (0, Key::BTN_Z) => Key::BTN_TRIGGER_HAPPY5,
+            (_, key) => key,
+        };
+        Some((event_type, key.code(), value))
+    }
+}
+
+/// Key map for a left joycon held sideways, with SL/SR on the top.
+///
+/// The controller is rotated 90° counter-clockwise, so the stick axes are rotated accordingly, the
+/// D-pad becomes the face buttons and SL/SR become the shoulder buttons.
+pub struct HorizontalLeftControllerKeyMap;
+
+impl HorizontalLeftControllerKeyMap {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl KeyMap for HorizontalLeftControllerKeyMap {
+    fn map_key(
+        &self,
+        _controller_id: usize,
+        event_type: EventType,
+        code: u16,
+        value: i32,
+    ) -> Option<(EventType, u16, i32)> {
+        match event_type {
+            EventType::KEY => {
+                let key = match Key::new(code) {
+                    Key::BTN_DPAD_DOWN => Key::BTN_EAST,
+                    Key::BTN_DPAD_RIGHT => Key::BTN_NORTH,
+                    Key::BTN_DPAD_LEFT => Key::BTN_SOUTH,
+                    Key::BTN_DPAD_UP => Key::BTN_WEST,
+                    // SL & SR
+                    Key::BTN_TR => Key::BTN_TL,
+                    Key::BTN_TR2 => Key::BTN_TR,
+                    // L & ZL
+                    Key::BTN_TL => Key::BTN_TL2,
+                    Key::BTN_TL2 => Key::BTN_TR2,
+                    // Minus & Capture
+                    Key::BTN_SELECT => Key::BTN_START,
+                    Key::BTN_Z => Key::BTN_MODE,
+                    Key::BTN_THUMBL => Key::BTN_THUMBL,
+                    _ => return None,
+                };
+                Some((event_type, key.code(), value))
+            }
+            EventType::ABSOLUTE => match AbsoluteAxisType(code) {
+                AbsoluteAxisType::ABS_X => Some((
+                    event_type,
+                    AbsoluteAxisType::ABS_Y.0,
+                    value.saturating_neg(),
+                )),
+                AbsoluteAxisType::ABS_Y => Some((event_type, AbsoluteAxisType::ABS_X.0, value)),
+                _ => None,
+            },
+            _ => Some((event_type, code, value)),
+        }
+    }
+}
+
+/// Key map for a right joycon held sideways, with SL/SR on the top.
+///
+/// The controller is rotated 90° clockwise, so the stick axes are rotated accordingly and reported
+/// as the left stick, the face buttons are rotated and SL/SR become the shoulder buttons.
+pub struct HorizontalRightControllerKeyMap;
+
+impl HorizontalRightControllerKeyMap {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl KeyMap for HorizontalRightControllerKeyMap {
+    fn map_key(
+        &self,
+        _controller_id: usize,
+        event_type: EventType,
+        code: u16,
+        value: i32,
+    ) -> Option<(EventType, u16, i32)> {
+        match event_type {
+            EventType::KEY => {
+                let key = match Key::new(code) {
+                    Key::BTN_NORTH => Key::BTN_EAST,
+                    Key::BTN_EAST => Key::BTN_SOUTH,
+                    Key::BTN_SOUTH => Key::BTN_WEST,
+                    Key::BTN_WEST => Key::BTN_NORTH,
+                    // SL & SR
+                    Key::BTN_TL => Key::BTN_TL,
+                    Key::BTN_TL2 => Key::BTN_TR,
+                    // R & ZR
+                    Key::BTN_TR => Key::BTN_TL2,
+                    Key::BTN_TR2 => Key::BTN_TR2,
+                    // Plus & Home
+                    Key::BTN_START => Key::BTN_START,
+                    Key::BTN_MODE => Key::BTN_MODE,
+                    Key::BTN_THUMBR => Key::BTN_THUMBL,
+                    _ => return None,
+                };
+                Some((event_type, key.code(), value))
+            }
+            EventType::ABSOLUTE => match AbsoluteAxisType(code) {
+                AbsoluteAxisType::ABS_RX => Some((event_type, AbsoluteAxisType::ABS_Y.0, value)),
+                AbsoluteAxisType::ABS_RY => Some((
+                    event_type,
+                    AbsoluteAxisType::ABS_X.0,
+                    value.saturating_neg(),
+                )),
+                _ => None,
+            },
+            _ => Some((event_type, code, value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(map: &impl KeyMap, from: Key) -> Option<Key> {
+        map.map_key(0, EventType::KEY, from.code(), 1)
+            .map(|(event_type, code, value)| {
+                assert_eq!(event_type, EventType::KEY);
+                assert_eq!(value, 1);
+                Key::new(code)
+            })
+    }
+
+    fn abs(
+        map: &impl KeyMap,
+        from: AbsoluteAxisType,
+        value: i32,
+    ) -> Option<(AbsoluteAxisType, i32)> {
+        map.map_key(0, EventType::ABSOLUTE, from.0, value)
+            .map(|(event_type, code, value)| {
+                assert_eq!(event_type, EventType::ABSOLUTE);
+                (AbsoluteAxisType(code), value)
+            })
+    }
+
+    #[test]
+    fn combined_extra_buttons() {
+        let map = CombinedControllerKeyMap::new();
+        let key = |controller_id, from: Key| {
+            map.map_key(controller_id, EventType::KEY, from.code(), 1)
+                .map(|(_, code, _)| Key::new(code))
+        };
+        assert_eq!(key(0, Key::BTN_TR), Some(Key::BTN_TRIGGER_HAPPY1));
+        assert_eq!(key(0, Key::BTN_TR2), Some(Key::BTN_TRIGGER_HAPPY2));
+        assert_eq!(key(1, Key::BTN_TL), Some(Key::BTN_TRIGGER_HAPPY3));
+        assert_eq!(key(1, Key::BTN_TL2), Some(Key::BTN_TRIGGER_HAPPY4));
+        assert_eq!(key(0, Key::BTN_Z), Some(Key::BTN_TRIGGER_HAPPY5));
+        // L and R keep their codes.
+        assert_eq!(key(0, Key::BTN_TL), Some(Key::BTN_TL));
+        assert_eq!(key(1, Key::BTN_TR), Some(Key::BTN_TR));
+        assert_eq!(
+            map.map_key(1, EventType::ABSOLUTE, AbsoluteAxisType::ABS_RX.0, 100),
+            Some((EventType::ABSOLUTE, AbsoluteAxisType::ABS_RX.0, 100))
+        );
+    }
+
+    #[test]
+    fn horizontal_left_keys() {
+        let map = HorizontalLeftControllerKeyMap::new();
+        let expected = [
+            (Key::BTN_DPAD_DOWN, Key::BTN_EAST),
+            (Key::BTN_DPAD_RIGHT, Key::BTN_NORTH),
+            (Key::BTN_DPAD_LEFT, Key::BTN_SOUTH),
+            (Key::BTN_DPAD_UP, Key::BTN_WEST),
+            (Key::BTN_TR, Key::BTN_TL),
+            (Key::BTN_TR2, Key::BTN_TR),
+            (Key::BTN_TL, Key::BTN_TL2),
+            (Key::BTN_TL2, Key::BTN_TR2),
+            (Key::BTN_SELECT, Key::BTN_START),
+            (Key::BTN_Z, Key::BTN_MODE),
+            (Key::BTN_THUMBL, Key::BTN_THUMBL),
+        ];
+        for (from, to) in expected {
+            assert_eq!(key(&map, from), Some(to), "{from:?}");
+        }
+        assert_eq!(key(&map, Key::BTN_EAST), None);
+    }
+
+    #[test]
+    fn horizontal_left_axes() {
+        let map = HorizontalLeftControllerKeyMap::new();
+        // Pushing the stick towards the D-pad points right when held sideways.
+        assert_eq!(
+            abs(&map, AbsoluteAxisType::ABS_Y, 32767),
+            Some((AbsoluteAxisType::ABS_X, 32767))
+        );
+        // Pushing the stick towards the rail points up when held sideways.
+        assert_eq!(
+            abs(&map, AbsoluteAxisType::ABS_X, 32767),
+            Some((AbsoluteAxisType::ABS_Y, -32767))
+        );
+        assert_eq!(
+            abs(&map, AbsoluteAxisType::ABS_X, -32767),
+            Some((AbsoluteAxisType::ABS_Y, 32767))
+        );
+        assert_eq!(abs(&map, AbsoluteAxisType::ABS_RX, 100), None);
+    }
+
+    #[test]
+    fn horizontal_right_keys() {
+        let map = HorizontalRightControllerKeyMap::new();
+        let expected = [
+            (Key::BTN_NORTH, Key::BTN_EAST),
+            (Key::BTN_EAST, Key::BTN_SOUTH),
+            (Key::BTN_SOUTH, Key::BTN_WEST),
+            (Key::BTN_WEST, Key::BTN_NORTH),
+            (Key::BTN_TL, Key::BTN_TL),
+            (Key::BTN_TL2, Key::BTN_TR),
+            (Key::BTN_TR, Key::BTN_TL2),
+            (Key::BTN_TR2, Key::BTN_TR2),
+            (Key::BTN_START, Key::BTN_START),
+            (Key::BTN_MODE, Key::BTN_MODE),
+            (Key::BTN_THUMBR, Key::BTN_THUMBL),
+        ];
+        for (from, to) in expected {
+            assert_eq!(key(&map, from), Some(to), "{from:?}");
+        }
+        assert_eq!(key(&map, Key::BTN_DPAD_UP), None);
+    }
+
+    #[test]
+    fn horizontal_right_axes() {
+        let map = HorizontalRightControllerKeyMap::new();
+        // Pushing the stick towards the rail points up when held sideways.
+        assert_eq!(
+            abs(&map, AbsoluteAxisType::ABS_RX, -32767),
+            Some((AbsoluteAxisType::ABS_Y, -32767))
+        );
+        assert_eq!(
+            abs(&map, AbsoluteAxisType::ABS_RX, 32767),
+            Some((AbsoluteAxisType::ABS_Y, 32767))
+        );
+        // Pushing the stick away from the face buttons points right when held sideways.
+        assert_eq!(
+            abs(&map, AbsoluteAxisType::ABS_RY, -32767),
+            Some((AbsoluteAxisType::ABS_X, 32767))
+        );
+        assert_eq!(abs(&map, AbsoluteAxisType::ABS_X, 100), None);
+    }
+
+    #[test]
+    fn horizontal_passes_sync_events() {
+        let sync = (EventType::SYNCHRONIZATION, 0, 0);
+        assert_eq!(
+            HorizontalLeftControllerKeyMap::new().map_key(0, sync.0, sync.1, sync.2),
+            Some(sync)
+        );
+        assert_eq!(
+            HorizontalRightControllerKeyMap::new().map_key(0, sync.0, sync.1, sync.2),
+            Some(sync)
+        );
+    }
+}
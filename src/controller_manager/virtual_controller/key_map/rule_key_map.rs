@@ -0,0 +1,245 @@
+use std::str::FromStr;
+
+use evdev::{AbsoluteAxisType, EventType, Key};
+
+use super::KeyMap;
+
+/// An input code, either a key or an absolute axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    Key(Key),
+    Axis(AbsoluteAxisType),
+}
+
+impl FromStr for Code {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Key::from_str(s)
+            .map(Code::Key)
+            .or_else(|_| AbsoluteAxisType::from_str(s).map(Code::Axis))
+            .map_err(|_| anyhow::anyhow!("Unknown key or axis: {s}"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// Remap a key to another key.
+    KeyToKey { from: Key, to: Key },
+    /// Set an axis to `value` while the key is pressed, and back to 0 when released.
+    KeyToAxis {
+        from: Key,
+        to: AbsoluteAxisType,
+        value: i32,
+    },
+    /// Press a key when the axis goes beyond `threshold`. A negative threshold presses the key
+    /// when the axis goes below it.
+    AxisToKey {
+        from: AbsoluteAxisType,
+        to: Key,
+        threshold: i32,
+    },
+    /// Remap an axis to another axis, optionally inverting it.
+    AxisToAxis {
+        from: AbsoluteAxisType,
+        to: AbsoluteAxisType,
+        invert: bool,
+    },
+}
+
+impl Rule {
+    fn source(&self) -> Code {
+        match *self {
+            Rule::KeyToKey { from, .. } | Rule::KeyToAxis { from, .. } => Code::Key(from),
+            Rule::AxisToKey { from, .. } | Rule::AxisToAxis { from, .. } => Code::Axis(from),
+        }
+    }
+
+    fn apply(&self, value: i32) -> (EventType, u16, i32) {
+        match *self {
+            Rule::KeyToKey { to, .. } => (EventType::KEY, to.code(), value),
+            Rule::KeyToAxis {
+                to, value: axis, ..
+            } => (EventType::ABSOLUTE, to.0, if value != 0 { axis } else { 0 }),
+            Rule::AxisToKey { to, threshold, .. } => {
+                let pressed = if threshold >= 0 {
+                    value >= threshold
+                } else {
+                    value <= threshold
+                };
+                (EventType::KEY, to.code(), pressed as i32)
+            }
+            Rule::AxisToAxis { to, invert, .. } => (
+                EventType::ABSOLUTE,
+                to.0,
+                if invert {
+                    value.saturating_neg()
+                } else {
+                    value
+                },
+            ),
+        }
+    }
+}
+
+/// A key map built from a table of rules. The first rule matching the source code and the
+/// controller id is applied. Keys and axes matching no rule are forwarded unchanged if
+/// `passthrough` is set, and dropped otherwise.
+pub struct RuleKeyMap {
+    rules: Vec<(Option<usize>, Rule)>,
+    passthrough: bool,
+}
+
+impl RuleKeyMap {
+    /// Create a key map from rules, each optionally scoped to a controller id.
+    pub fn new(rules: Vec<(Option<usize>, Rule)>, passthrough: bool) -> Self {
+        Self { rules, passthrough }
+    }
+}
+
+impl KeyMap for RuleKeyMap {
+    fn map_key(
+        &self,
+        controller_id: usize,
+        event_type: EventType,
+        code: u16,
+        value: i32,
+    ) -> Option<(EventType, u16, i32)> {
+        let source = match event_type {
+            EventType::KEY => Code::Key(Key::new(code)),
+            EventType::ABSOLUTE => Code::Axis(AbsoluteAxisType(code)),
+            _ => return Some((event_type, code, value)),
+        };
+
+        self.rules
+            .iter()
+            .find(|(scope, rule)| {
+                scope.is_none_or(|id| id == controller_id) && rule.source() == source
+            })
+            .map(|(_, rule)| rule.apply(value))
+            .or(self.passthrough.then_some((event_type, code, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_code() {
+        assert_eq!(
+            "BTN_EAST".parse::<Code>().unwrap(),
+            Code::Key(Key::BTN_EAST)
+        );
+        assert_eq!(
+            "ABS_RX".parse::<Code>().unwrap(),
+            Code::Axis(AbsoluteAxisType::ABS_RX)
+        );
+        assert!("BTN_NOPE".parse::<Code>().is_err());
+    }
+
+    #[test]
+    fn swap_and_scope() {
+        let map = RuleKeyMap::new(
+            vec![
+                (
+                    None,
+                    Rule::KeyToKey {
+                        from: Key::BTN_EAST,
+                        to: Key::BTN_SOUTH,
+                    },
+                ),
+                (
+                    None,
+                    Rule::KeyToKey {
+                        from: Key::BTN_SOUTH,
+                        to: Key::BTN_EAST,
+                    },
+                ),
+                (
+                    Some(1),
+                    Rule::AxisToAxis {
+                        from: AbsoluteAxisType::ABS_X,
+                        to: AbsoluteAxisType::ABS_Y,
+                        invert: true,
+                    },
+                ),
+            ],
+            false,
+        );
+
+        let key = |id, key: Key| map.map_key(id, EventType::KEY, key.code(), 1);
+        assert_eq!(
+            key(0, Key::BTN_EAST),
+            Some((EventType::KEY, Key::BTN_SOUTH.code(), 1))
+        );
+        assert_eq!(
+            key(0, Key::BTN_SOUTH),
+            Some((EventType::KEY, Key::BTN_EAST.code(), 1))
+        );
+        assert_eq!(key(0, Key::BTN_NORTH), None);
+
+        let abs = |id| map.map_key(id, EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, 100);
+        assert_eq!(abs(0), None);
+        assert_eq!(
+            abs(1),
+            Some((EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, -100))
+        );
+
+        assert_eq!(
+            map.map_key(0, EventType::SYNCHRONIZATION, 0, 0),
+            Some((EventType::SYNCHRONIZATION, 0, 0))
+        );
+    }
+
+    #[test]
+    fn key_to_axis_and_axis_to_key() {
+        let map = RuleKeyMap::new(
+            vec![
+                (
+                    None,
+                    Rule::KeyToAxis {
+                        from: Key::BTN_DPAD_LEFT,
+                        to: AbsoluteAxisType::ABS_HAT0X,
+                        value: -1,
+                    },
+                ),
+                (
+                    None,
+                    Rule::AxisToKey {
+                        from: AbsoluteAxisType::ABS_RY,
+                        to: Key::BTN_TR2,
+                        threshold: -16000,
+                    },
+                ),
+            ],
+            true,
+        );
+
+        let hat = |value| map.map_key(0, EventType::KEY, Key::BTN_DPAD_LEFT.code(), value);
+        assert_eq!(
+            hat(1),
+            Some((EventType::ABSOLUTE, AbsoluteAxisType::ABS_HAT0X.0, -1))
+        );
+        assert_eq!(
+            hat(0),
+            Some((EventType::ABSOLUTE, AbsoluteAxisType::ABS_HAT0X.0, 0))
+        );
+
+        let trigger =
+            |value| map.map_key(0, EventType::ABSOLUTE, AbsoluteAxisType::ABS_RY.0, value);
+        assert_eq!(
+            trigger(-20000),
+            Some((EventType::KEY, Key::BTN_TR2.code(), 1))
+        );
+        assert_eq!(
+            trigger(-100),
+            Some((EventType::KEY, Key::BTN_TR2.code(), 0))
+        );
+
+        assert_eq!(
+            map.map_key(0, EventType::KEY, Key::BTN_NORTH.code(), 1),
+            Some((EventType::KEY, Key::BTN_NORTH.code(), 1))
+        );
+    }
+}
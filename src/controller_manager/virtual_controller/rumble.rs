@@ -0,0 +1,233 @@
+use std::{fmt, str::FromStr};
+
+use evdev::{FFEffectData, FFEffectKind};
+use serde::Deserialize;
+
+/// Where the force feedback effects of a virtual controller go.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RumbleRouting {
+    /// Every physical device plays every effect.
+    Mirror,
+    /// Only the left half of a combined controller plays the effects.
+    Left,
+    /// Only the right half of a combined controller plays the effects.
+    Right,
+    /// The left half plays the strong motor of the rumble effects and the right half the weak
+    /// motor. The other effects are mirrored.
+    Split,
+}
+
+impl FromStr for RumbleRouting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mirror" => Ok(Self::Mirror),
+            "left" => Ok(Self::Left),
+            "right" => Ok(Self::Right),
+            "split" => Ok(Self::Split),
+            _ => Err(anyhow::anyhow!("Unknown rumble routing: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for RumbleRouting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Mirror => "mirror",
+            Self::Left => "left",
+            Self::Right => "right",
+            Self::Split => "split",
+        })
+    }
+}
+
+/// The part of a virtual controller a physical device is. A combined controller keeps its left
+/// half first, while a controller used alone is whole and plays every effect.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Half {
+    Left,
+    Right,
+    Whole,
+}
+
+impl Half {
+    pub fn of(physical_device_id: usize, physical_devices: usize) -> Self {
+        match (physical_devices, physical_device_id) {
+            (1, _) => Self::Whole,
+            (_, 0) => Self::Left,
+            _ => Self::Right,
+        }
+    }
+}
+
+/// The effect a physical device plays for an effect of its virtual controller, scaled by
+/// `intensity`, or `None` if the routing leaves the device out.
+pub fn route(
+    data: FFEffectData,
+    routing: RumbleRouting,
+    half: Half,
+    intensity: f64,
+) -> Option<FFEffectData> {
+    let kind = match (routing, half, data.kind) {
+        (_, Half::Whole, kind) | (RumbleRouting::Mirror, _, kind) => kind,
+        (RumbleRouting::Left, Half::Left, kind) | (RumbleRouting::Right, Half::Right, kind) => kind,
+        (RumbleRouting::Left, _, _) | (RumbleRouting::Right, _, _) => return None,
+        (
+            RumbleRouting::Split,
+            half,
+            FFEffectKind::Rumble {
+                strong_magnitude,
+                weak_magnitude,
+            },
+        ) => match half {
+            Half::Left => FFEffectKind::Rumble {
+                strong_magnitude,
+                weak_magnitude: 0,
+            },
+            _ => FFEffectKind::Rumble {
+                strong_magnitude: 0,
+                weak_magnitude,
+            },
+        },
+        (RumbleRouting::Split, _, kind) => kind,
+    };
+
+    Some(FFEffectData {
+        kind: scale(kind, intensity),
+        ..data
+    })
+}
+
+/// Scale the strength of an effect. Float to integer casts saturate.
+fn scale(kind: FFEffectKind, intensity: f64) -> FFEffectKind {
+    let unsigned = |value: u16| (value as f64 * intensity) as u16;
+    let signed = |value: i16| (value as f64 * intensity) as i16;
+    match kind {
+        FFEffectKind::Rumble {
+            strong_magnitude,
+            weak_magnitude,
+        } => FFEffectKind::Rumble {
+            strong_magnitude: unsigned(strong_magnitude),
+            weak_magnitude: unsigned(weak_magnitude),
+        },
+        FFEffectKind::Periodic {
+            waveform,
+            period,
+            magnitude,
+            offset,
+            phase,
+            envelope,
+        } => FFEffectKind::Periodic {
+            waveform,
+            period,
+            magnitude: signed(magnitude),
+            offset: signed(offset),
+            phase,
+            envelope,
+        },
+        FFEffectKind::Constant { level, envelope } => FFEffectKind::Constant {
+            level: signed(level),
+            envelope,
+        },
+        FFEffectKind::Ramp {
+            start_level,
+            end_level,
+            envelope,
+        } => FFEffectKind::Ramp {
+            start_level: signed(start_level),
+            end_level: signed(end_level),
+            envelope,
+        },
+        kind => kind,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::{FFReplay, FFTrigger};
+
+    fn rumble(strong_magnitude: u16, weak_magnitude: u16) -> FFEffectData {
+        FFEffectData {
+            direction: 0,
+            trigger: FFTrigger::default(),
+            replay: FFReplay {
+                length: 500,
+                delay: 0,
+            },
+            kind: FFEffectKind::Rumble {
+                strong_magnitude,
+                weak_magnitude,
+            },
+        }
+    }
+
+    fn magnitudes(data: Option<FFEffectData>) -> Option<(u16, u16)> {
+        data.map(|data| match data.kind {
+            FFEffectKind::Rumble {
+                strong_magnitude,
+                weak_magnitude,
+            } => (strong_magnitude, weak_magnitude),
+            kind => panic!("Not a rumble effect: {kind:?}"),
+        })
+    }
+
+    #[test]
+    fn halves() {
+        assert_eq!(Half::of(0, 1), Half::Whole);
+        assert_eq!(Half::of(0, 2), Half::Left);
+        assert_eq!(Half::of(1, 2), Half::Right);
+    }
+
+    #[test]
+    fn routings() {
+        let effect = rumble(1000, 2000);
+        let routed = |routing, half| magnitudes(route(effect, routing, half, 1.0));
+        assert_eq!(
+            routed(RumbleRouting::Mirror, Half::Right),
+            Some((1000, 2000))
+        );
+        assert_eq!(routed(RumbleRouting::Left, Half::Left), Some((1000, 2000)));
+        assert_eq!(routed(RumbleRouting::Left, Half::Right), None);
+        assert_eq!(routed(RumbleRouting::Right, Half::Left), None);
+        assert_eq!(routed(RumbleRouting::Split, Half::Left), Some((1000, 0)));
+        assert_eq!(routed(RumbleRouting::Split, Half::Right), Some((0, 2000)));
+        // A controller used alone plays everything.
+        assert_eq!(
+            routed(RumbleRouting::Right, Half::Whole),
+            Some((1000, 2000))
+        );
+        assert_eq!(
+            routed(RumbleRouting::Split, Half::Whole),
+            Some((1000, 2000))
+        );
+        assert_eq!(
+            route(effect, RumbleRouting::Mirror, Half::Left, 1.0)
+                .unwrap()
+                .replay,
+            effect.replay
+        );
+    }
+
+    #[test]
+    fn intensity() {
+        let routed = |effect, intensity| {
+            magnitudes(route(effect, RumbleRouting::Mirror, Half::Left, intensity))
+        };
+        assert_eq!(routed(rumble(1000, 2000), 0.5), Some((500, 1000)));
+        assert_eq!(routed(rumble(1000, 2000), 0.0), Some((0, 0)));
+        assert_eq!(routed(rumble(40000, 2000), 2.0), Some((u16::MAX, 4000)));
+    }
+
+    #[test]
+    fn parse_routing() {
+        assert_eq!(
+            "split".parse::<RumbleRouting>().unwrap(),
+            RumbleRouting::Split
+        );
+        assert!("both".parse::<RumbleRouting>().is_err());
+        assert_eq!(RumbleRouting::Left.to_string(), "left");
+    }
+}
@@ -0,0 +1,344 @@
+use anyhow::Result as Anyhow;
+use evdev::{AbsoluteAxisType, EventType, InputEvent};
+use serde::Deserialize;
+
+use super::super::controller::Controller;
+use crate::config::{Config, StickConfig};
+
+/// A stick of a controller, by the axes it reports.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StickSide {
+    /// `ABS_X` and `ABS_Y`.
+    Left,
+    /// `ABS_RX` and `ABS_RY`.
+    Right,
+}
+
+impl StickSide {
+    const ALL: [Self; 2] = [Self::Left, Self::Right];
+
+    fn axes(self) -> [AbsoluteAxisType; 2] {
+        match self {
+            Self::Left => [AbsoluteAxisType::ABS_X, AbsoluteAxisType::ABS_Y],
+            Self::Right => [AbsoluteAxisType::ABS_RX, AbsoluteAxisType::ABS_RY],
+        }
+    }
+}
+
+/// How the deadzones of a stick are measured.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeadzoneShape {
+    /// On the distance of the stick from its center, keeping its direction.
+    Radial,
+    /// On each axis on its own.
+    Axial,
+}
+
+/// How the tilt of a stick out of its deadzones maps to the tilt reported.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseCurve {
+    Linear,
+    /// Finer control near the center.
+    Quadratic,
+    /// Linear interpolation between the `curve_points` of the configuration.
+    Custom,
+}
+
+/// The stick processing of a physical device. Both axes of a stick are processed together, so an
+/// event of one of them reports both.
+pub struct StickProcessor {
+    sticks: Vec<Stick>,
+}
+
+struct Stick {
+    axes: [AbsoluteAxisType; 2],
+    config: StickConfig,
+    calibrations: [Calibration; 2],
+    /// The ranges of the physical axes, which the processed values are reported in.
+    ranges: [(i32, i32); 2],
+    /// The last raw values of the axes.
+    raw: [i32; 2],
+}
+
+/// The raw values of an axis at rest and at full tilt.
+#[derive(Copy, Clone, Debug)]
+struct Calibration {
+    center: f64,
+    min: f64,
+    max: f64,
+}
+
+impl StickProcessor {
+    /// Process the sticks of a controller having settings in the configuration.
+    pub fn new(controller: &Controller, config: &Config) -> Anyhow<Self> {
+        let device = controller.as_ref();
+        let mut sticks = vec![];
+        if let Some(supported) = device.supported_absolute_axes() {
+            let state = device.get_abs_state()?;
+            for side in StickSide::ALL {
+                let axes = side.axes();
+                if !axes.iter().all(|&axis| supported.contains(axis)) {
+                    continue;
+                }
+                let Some(stick_config) = config.stick(controller.uniq(), side) else {
+                    continue;
+                };
+                let info = axes.map(|axis| state[axis.0 as usize]);
+                let mut stick = Stick::new(
+                    axes,
+                    stick_config.clone(),
+                    info.map(|info| (info.minimum, info.maximum)),
+                );
+                stick.raw = info.map(|info| info.value);
+                sticks.push(stick);
+            }
+        }
+
+        Ok(Self { sticks })
+    }
+
+    /// The events reported for an input event of the physical device.
+    pub fn process(&mut self, event: InputEvent) -> Vec<InputEvent> {
+        if event.event_type() != EventType::ABSOLUTE {
+            return vec![event];
+        }
+        for stick in &mut self.sticks {
+            if let Some(i) = stick.axes.iter().position(|axis| axis.0 == event.code()) {
+                stick.raw[i] = event.value();
+                let values = stick.values();
+                return (0..2)
+                    .map(|i| InputEvent::new(EventType::ABSOLUTE, stick.axes[i].0, values[i]))
+                    .collect();
+            }
+        }
+
+        vec![event]
+    }
+
+    /// Forget the positions of the sticks, e.g. for a reconnected controller.
+    pub fn recenter(&mut self) {
+        for stick in &mut self.sticks {
+            stick.raw = stick
+                .calibrations
+                .map(|calibration| calibration.center.round() as i32);
+        }
+    }
+}
+
+impl Stick {
+    /// `ranges` are the minimum and maximum values of the physical axes.
+    fn new(axes: [AbsoluteAxisType; 2], config: StickConfig, ranges: [(i32, i32); 2]) -> Self {
+        let calibrations = [0, 1].map(|i| {
+            let (min, max) = ranges[i];
+            Calibration {
+                center: config
+                    .center
+                    .map_or((min as f64 + max as f64) / 2.0, |c| c[i] as f64),
+                min: config.min.map_or(min, |m| m[i]) as f64,
+                max: config.max.map_or(max, |m| m[i]) as f64,
+            }
+        });
+        let raw = calibrations.map(|calibration| calibration.center.round() as i32);
+
+        Self {
+            axes,
+            config,
+            calibrations,
+            ranges,
+            raw,
+        }
+    }
+
+    /// The processed values of the axes.
+    fn values(&self) -> [i32; 2] {
+        let [x, y] = [0, 1].map(|i| self.calibrations[i].normalize(self.raw[i]));
+        let shaped = match self.config.deadzone_shape {
+            DeadzoneShape::Radial => {
+                let distance = x.hypot(y);
+                if distance == 0.0 {
+                    [0.0, 0.0]
+                } else {
+                    let scale = self.shape(distance.min(1.0)) / distance;
+                    [x * scale, y * scale]
+                }
+            }
+            DeadzoneShape::Axial => [x, y].map(|v| self.shape(v.abs()).copysign(v)),
+        };
+
+        [0, 1].map(|i| denormalize(shaped[i], self.ranges[i]))
+    }
+
+    /// Shape a tilt between 0 and 1 through the deadzones and the response curve.
+    fn shape(&self, tilt: f64) -> f64 {
+        let config = &self.config;
+        if tilt <= config.deadzone {
+            return 0.0;
+        }
+        let live = 1.0 - config.deadzone - config.outer_deadzone;
+        let tilt = ((tilt - config.deadzone) / live).min(1.0);
+        let tilt = match config.curve {
+            ResponseCurve::Linear => tilt,
+            ResponseCurve::Quadratic => tilt * tilt,
+            ResponseCurve::Custom => interpolate(&config.curve_points, tilt),
+        };
+
+        config.anti_deadzone + (1.0 - config.anti_deadzone) * tilt
+    }
+}
+
+impl Calibration {
+    /// A raw value as a tilt between -1 and 1.
+    fn normalize(&self, value: i32) -> f64 {
+        let value = value as f64 - self.center;
+        let span = if value >= 0.0 {
+            self.max - self.center
+        } else {
+            self.center - self.min
+        };
+        if span <= 0.0 {
+            return 0.0;
+        }
+
+        (value / span).clamp(-1.0, 1.0)
+    }
+}
+
+/// A tilt between -1 and 1 as a value of an axis range.
+fn denormalize(tilt: f64, (min, max): (i32, i32)) -> i32 {
+    let center = (min as f64 + max as f64) / 2.0;
+    let span = if tilt >= 0.0 {
+        max as f64 - center
+    } else {
+        center - min as f64
+    };
+
+    (center + tilt * span).round() as i32
+}
+
+/// Interpolate a curve through `points`, sorted by input, starting at `[0, 0]` and ending at
+/// `[1, 1]` unless they give other ends.
+fn interpolate(points: &[[f64; 2]], tilt: f64) -> f64 {
+    let mut previous = [0.0, 0.0];
+    for &point in points.iter().chain(&[[1.0, 1.0]]) {
+        if tilt <= point[0] {
+            if point[0] == previous[0] {
+                return point[1];
+            }
+            let t = (tilt - previous[0]) / (point[0] - previous[0]);
+            return previous[1] + t * (point[1] - previous[1]);
+        }
+        previous = point;
+    }
+
+    previous[1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RANGE: (i32, i32) = (-32767, 32767);
+
+    fn stick(config: StickConfig) -> Stick {
+        Stick::new(StickSide::Left.axes(), config, [RANGE, RANGE])
+    }
+
+    fn values(stick: &mut Stick, raw: [i32; 2]) -> [i32; 2] {
+        stick.raw = raw;
+        stick.values()
+    }
+
+    #[test]
+    fn calibration() {
+        let mut stick = stick(StickConfig {
+            center: Some([1000, -500]),
+            min: Some([-20000, -30000]),
+            max: Some([30000, 25000]),
+            deadzone_shape: DeadzoneShape::Axial,
+            ..Default::default()
+        });
+        assert_eq!(values(&mut stick, [1000, -500]), [0, 0]);
+        assert_eq!(values(&mut stick, [30000, -30000]), [32767, -32767]);
+        assert_eq!(values(&mut stick, [-9500, 12250]), [-16384, 16384]);
+        // Beyond the calibrated range is full tilt.
+        assert_eq!(values(&mut stick, [32767, 32767]), [32767, 32767]);
+    }
+
+    #[test]
+    fn radial_deadzone() {
+        let mut stick = stick(StickConfig {
+            deadzone: 0.2,
+            outer_deadzone: 0.2,
+            ..Default::default()
+        });
+        // Each axis is inside the deadzone, but not the stick.
+        assert_eq!(values(&mut stick, [4000, 4000]), [0, 0]);
+        let [x, y] = values(&mut stick, [6000, 6000]);
+        assert!(x > 0 && x == y);
+        assert_eq!(values(&mut stick, [16384, 0]), [16384, 0]);
+        assert_eq!(values(&mut stick, [0, -27000]), [0, -32767]);
+        let [x, y] = values(&mut stick, [30000, 30000]);
+        assert_eq!((x, y), (23170, 23170));
+    }
+
+    #[test]
+    fn axial_deadzone() {
+        let mut stick = stick(StickConfig {
+            deadzone: 0.2,
+            deadzone_shape: DeadzoneShape::Axial,
+            anti_deadzone: 0.25,
+            ..Default::default()
+        });
+        assert_eq!(values(&mut stick, [6000, 32767]), [0, 32767]);
+        // Leaving the deadzone jumps to the anti-deadzone.
+        let [x, _] = values(&mut stick, [6600, 0]);
+        assert!((8192..8300).contains(&x));
+        assert_eq!(values(&mut stick, [-32767, 0]), [-32767, 0]);
+    }
+
+    #[test]
+    fn curves() {
+        let quadratic = stick(StickConfig {
+            curve: ResponseCurve::Quadratic,
+            ..Default::default()
+        });
+        assert_eq!(quadratic.shape(0.5), 0.25);
+        let custom = stick(StickConfig {
+            curve: ResponseCurve::Custom,
+            curve_points: vec![[0.5, 0.2], [0.8, 0.8]],
+            ..Default::default()
+        });
+        assert!((custom.shape(0.25) - 0.1).abs() < 1e-9);
+        assert!((custom.shape(0.65) - 0.5).abs() < 1e-9);
+        assert!((custom.shape(0.9) - 0.9).abs() < 1e-9);
+        assert_eq!(custom.shape(1.0), 1.0);
+    }
+
+    #[test]
+    fn both_axes_reported() {
+        let mut processor = StickProcessor {
+            sticks: vec![stick(StickConfig {
+                deadzone: 0.1,
+                ..Default::default()
+            })],
+        };
+        let events = processor.process(InputEvent::new(
+            EventType::ABSOLUTE,
+            AbsoluteAxisType::ABS_Y.0,
+            32767,
+        ));
+        let values: Vec<_> = events.iter().map(|e| (e.code(), e.value())).collect();
+        assert_eq!(
+            values,
+            [
+                (AbsoluteAxisType::ABS_X.0, 0),
+                (AbsoluteAxisType::ABS_Y.0, 32767)
+            ]
+        );
+        let hat = InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_HAT0X.0, 1);
+        assert_eq!(processor.process(hat).len(), 1);
+    }
+}
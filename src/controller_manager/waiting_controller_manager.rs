@@ -24,6 +24,9 @@ impl WaitingControllerManager {
         controller: Rc<RefCell<Controller>>,
         poll_manager: &mut PollManager<ControllerManager, Anyhow<ControllerMessage>>,
     ) -> Anyhow<()> {
+        // Controllers coming back from a group may still have buttons held.
+        controller.borrow_mut().reset_buttons_state();
+
         let callback = Box::new({
             let controller = controller.clone();
             move |_ctx: &mut ControllerManager| {
@@ -58,6 +61,17 @@ impl WaitingControllerManager {
         }
     }
 
+    /// Get all the waiting controllers, sorted by token.
+    pub fn controllers(&self) -> Vec<(usize, Rc<RefCell<Controller>>)> {
+        let mut controllers: Vec<_> = self
+            .controllers
+            .iter()
+            .map(|(&token, (_, controller))| (token, controller.clone()))
+            .collect();
+        controllers.sort_by_key(|(token, _)| *token);
+        controllers
+    }
+
     pub fn get_controller(&self, token: usize) -> Anyhow<Rc<RefCell<Controller>>> {
         self.controllers
             .get(&token)
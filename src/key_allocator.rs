@@ -3,17 +3,19 @@ use bit_set::BitSet;
 
 pub struct KeyAllocator {
     bitmap: BitSet,
+    capacity: usize,
 }
 
 impl KeyAllocator {
     pub fn new(capacity: usize) -> Self {
         Self {
             bitmap: BitSet::with_capacity(capacity),
+            capacity,
         }
     }
 
     pub fn allocate(&mut self) -> Anyhow<usize> {
-        for i in 0..self.bitmap.len() {
+        for i in 0..self.capacity {
             if !self.bitmap.contains(i) {
                 self.bitmap.insert(i);
                 return Ok(i);
@@ -24,7 +26,7 @@ impl KeyAllocator {
     }
 
     pub fn occupy(&mut self, key: usize) -> Anyhow<()> {
-        if self.bitmap.contains(key) {
+        if self.bitmap.contains(key) || key >= self.capacity {
             Err(anyhow::anyhow!(
                 "{key} is already allocated or is bigger than the capacity"
             ))?;
@@ -38,3 +40,22 @@ impl KeyAllocator {
         self.bitmap.remove(key);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_lowest_free_key() {
+        let mut allocator = KeyAllocator::new(3);
+        assert_eq!(allocator.allocate().unwrap(), 0);
+        allocator.occupy(1).unwrap();
+        assert_eq!(allocator.allocate().unwrap(), 2);
+        assert!(allocator.allocate().is_err());
+
+        allocator.release(0);
+        assert_eq!(allocator.allocate().unwrap(), 0);
+        assert!(allocator.occupy(1).is_err());
+        assert!(allocator.occupy(3).is_err());
+    }
+}
@@ -1,9 +1,14 @@
 use anyhow::Result as Anyhow;
-use controller_manager::ControllerManager;
+use config::Config;
+use control::ControlServer;
+use controller_manager::{ControllerManager, ControllerMessage};
+use nix::sys::signal::Signal;
 use poll_manager::PollManager;
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsRawFd, BorrowedFd};
 use udev_detector::JoyconUdevDetector;
 
+mod config;
+mod control;
 mod controller_manager;
 mod key_allocator;
 mod poll_manager;
@@ -15,25 +20,65 @@ const UDEV_KEY: usize = KEY_CAPACITY - 1;
 fn main() -> Anyhow<()> {
     println!("Joycombindered starts!");
 
-    let mut controller_manager = ControllerManager::new();
+    let config_path = config::config_path_from_args(std::env::args().skip(1))?;
+    let config = Config::load(&config_path)?;
+    let udev_tag = config.udev_tag.clone();
+    let control_socket = config.control_socket.clone();
+
+    let mut controller_manager = ControllerManager::new(config, config_path)?;
     let mut poll_manager = PollManager::new()?;
     controller_manager.init(&mut poll_manager)?;
 
     // Create the first ever udev monitor add register the callback.
-    let udev_monitor = JoyconUdevDetector::monitor()?;
-    let epfd = udev_monitor.as_raw_fd();
+    let udev_monitor = JoyconUdevDetector::monitor(&udev_tag)?;
+    let udev_fd = udev_monitor.as_raw_fd();
     let callback = JoyconUdevDetector::callback(udev_monitor);
     poll_manager.subscribe_with_key(
         UDEV_KEY,
-        epfd,
+        udev_fd,
         polling::Event::readable(0),
         polling::PollMode::Level,
         Box::new(callback),
     )?;
 
-    loop {
+    // Accept requests from `joycombinerctl` on the control socket.
+    let listener = ControlServer::bind(&control_socket)?;
+    let control_fd = listener.as_raw_fd();
+    let control_key = poll_manager.subscribe(
+        control_fd,
+        polling::Event::readable(0),
+        polling::PollMode::Level,
+        Box::new(ControlServer::callback(listener)),
+    )?;
+
+    let signals_key = poll_manager.add_signals(
+        &[Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP],
+        |_ctx: &mut ControllerManager, signal| signal.map(ControllerMessage::Signal),
+    )?;
+
+    while controller_manager.is_running() {
         if let Err(e) = controller_manager.poll(&mut poll_manager) {
             eprintln!("{e}");
         }
     }
+
+    controller_manager.shutdown(&mut poll_manager)?;
+    // # Safety
+    //
+    // The udev monitor and the control socket are open until their callbacks drop, after they are
+    // deleted from the poller.
+    let (udev_fd, control_fd) = unsafe {
+        (
+            BorrowedFd::borrow_raw(udev_fd),
+            BorrowedFd::borrow_raw(control_fd),
+        )
+    };
+    poll_manager.remove(UDEV_KEY, udev_fd)?;
+    poll_manager.remove(control_key, control_fd)?;
+    poll_manager.remove_signals(signals_key)?;
+    if let Err(e) = std::fs::remove_file(&control_socket) {
+        eprintln!("Failed to remove the control socket: {e}");
+    }
+
+    Ok(())
 }
@@ -1,6 +1,19 @@
 use anyhow::Result as Anyhow;
+use nix::{
+    errno::Errno,
+    sys::{
+        signal::{SigSet, Signal},
+        signalfd::{SfdFlags, SignalFd},
+        time::TimeSpec,
+        timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags},
+    },
+};
 use polling::{AsRawSource, AsSource, Events, Poller};
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    os::fd::{AsRawFd, BorrowedFd, RawFd},
+    time::Duration,
+};
 
 use crate::key_allocator::KeyAllocator;
 
@@ -23,6 +36,9 @@ pub struct PollManager<Ctx, Message> {
     poller: Poller,
     callback_map: HashMap<usize, Box<dyn PollCallback<Ctx, Message>>>,
     callback_key_allocator: KeyAllocator,
+    timers: HashMap<usize, TimerFd>,
+    /// The signalfds subscribed, owned by their callbacks.
+    signals: HashMap<usize, RawFd>,
 }
 
 #[allow(unused)]
@@ -32,6 +48,8 @@ impl<Ctx, Message> PollManager<Ctx, Message> {
             poller: Poller::new()?,
             callback_map: HashMap::new(),
             callback_key_allocator: KeyAllocator::new(KEY_CAPACITY),
+            timers: HashMap::new(),
+            signals: HashMap::new(),
         })
     }
 
@@ -43,6 +61,14 @@ impl<Ctx, Message> PollManager<Ctx, Message> {
             .iter()
             .map(|event| {
                 let key = event.key;
+                if let Some(timer) = self.timers.get(&key) {
+                    // Consume the expirations, or the level-triggered timer keeps firing.
+                    match timer.wait() {
+                        Ok(()) | Err(Errno::EAGAIN) => {}
+                        Err(e) => Err(e)?,
+                    }
+                }
+
                 self.callback_map
                     .get_mut(&key)
                     .ok_or_else(|| {
@@ -91,14 +117,112 @@ impl<Ctx, Message> PollManager<Ctx, Message> {
         Ok(())
     }
 
-    /// Remove a scubscribtion.
+    /// Remove a scubscribtion. The source is deleted from the poller before the callback drops,
+    /// so the callback may own it.
     pub fn remove(&mut self, key: usize, source: impl AsSource) -> Anyhow<()> {
+        let deleted = self.poller.delete(source);
         self.callback_map.remove(&key);
-        self.poller.delete(source)?;
+        self.callback_key_allocator.release(key);
+        deleted?;
 
         Ok(())
     }
 
+    /// Add a timer calling `callback` after `delay`, then every `interval` if given. A one-shot
+    /// timer stays subscribed after firing until it is removed with [`PollManager::remove_timer`].
+    pub fn add_timer(
+        &mut self,
+        delay: Duration,
+        interval: Option<Duration>,
+        callback: Box<dyn PollCallback<Ctx, Message>>,
+    ) -> Anyhow<usize> {
+        let timer = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+        )?;
+        // A zero expiration disarms the timer, so fire as soon as possible instead.
+        let delay = TimeSpec::from(delay.max(Duration::from_nanos(1)));
+        let expiration = match interval {
+            Some(interval) => {
+                Expiration::IntervalDelayed(delay, interval.max(Duration::from_nanos(1)).into())
+            }
+            None => Expiration::OneShot(delay),
+        };
+        timer.set(expiration, TimerSetTimeFlags::empty())?;
+
+        let key = self.subscribe(
+            timer.as_raw_fd(),
+            polling::Event::readable(0),
+            polling::PollMode::Level,
+            callback,
+        )?;
+        self.timers.insert(key, timer);
+
+        Ok(key)
+    }
+
+    /// Block `signals` from their default handling and deliver them through a signalfd, calling
+    /// `callback` with each received signal, or the error reading it.
+    pub fn add_signals(
+        &mut self,
+        signals: &[Signal],
+        callback: impl FnMut(&mut Ctx, Anyhow<Signal>) -> Message + 'static,
+    ) -> Anyhow<usize>
+    where
+        Ctx: 'static,
+        Message: 'static,
+    {
+        let mut mask = SigSet::empty();
+        for &signal in signals {
+            mask.add(signal);
+        }
+        mask.thread_block()?;
+        let signal_fd =
+            SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+
+        let fd = signal_fd.as_raw_fd();
+        let key = self.subscribe(
+            fd,
+            polling::Event::readable(0),
+            polling::PollMode::Level,
+            Box::new(SignalCallback {
+                signal_fd,
+                callback,
+            }),
+        )?;
+        self.signals.insert(key, fd);
+
+        Ok(key)
+    }
+
+    /// Stop receiving the signals subscribed with [`PollManager::add_signals`]. They stay blocked.
+    pub fn remove_signals(&mut self, key: usize) -> Anyhow<()> {
+        let fd = self
+            .signals
+            .remove(&key)
+            .ok_or_else(|| anyhow::anyhow!("No signals for key {key}"))?;
+
+        // # Safety
+        //
+        // The signalfd is open until its callback drops, after it is deleted from the poller.
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        self.remove(key, fd)
+    }
+
+    /// Stop and remove a timer.
+    pub fn remove_timer(&mut self, key: usize) -> Anyhow<()> {
+        let timer = self
+            .timers
+            .remove(&key)
+            .ok_or_else(|| anyhow::anyhow!("No timer for key {key}"))?;
+
+        // # Safety
+        //
+        // The timer fd is open until the timer drops at the end of this function.
+        let fd = unsafe { BorrowedFd::borrow_raw(timer.as_raw_fd()) };
+        self.remove(key, fd)
+    }
+
     /// Modify a subcribtion.
     pub fn modify(
         &mut self,
@@ -113,3 +237,132 @@ impl<Ctx, Message> PollManager<Ctx, Message> {
         Ok(())
     }
 }
+
+struct SignalCallback<F> {
+    signal_fd: SignalFd,
+    callback: F,
+}
+
+impl<Ctx, Message, F> PollCallback<Ctx, Message> for SignalCallback<F>
+where
+    F: FnMut(&mut Ctx, Anyhow<Signal>) -> Message,
+{
+    fn call(&mut self, ctx: &mut Ctx) -> Message {
+        let signal = self
+            .signal_fd
+            .read_signal()
+            .map_err(anyhow::Error::from)
+            .and_then(|info| {
+                let info = info.ok_or_else(|| anyhow::anyhow!("No pending signal"))?;
+                Ok(Signal::try_from(info.ssi_signo as i32)?)
+            });
+        (self.callback)(ctx, signal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    fn poll_keys(poll_manager: &mut PollManager<usize, usize>, ctx: &mut usize) -> Vec<usize> {
+        poll_manager
+            .poll(ctx)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn remove_releases_key() {
+        let mut poll_manager = PollManager::<(), ()>::new().unwrap();
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let subscribe = |poll_manager: &mut PollManager<(), ()>| {
+            poll_manager
+                .subscribe(
+                    &stream,
+                    polling::Event::readable(0),
+                    polling::PollMode::Level,
+                    Box::new(|_: &mut ()| ()),
+                )
+                .unwrap()
+        };
+
+        let key = subscribe(&mut poll_manager);
+        poll_manager.remove(key, &stream).unwrap();
+        // The key is free again.
+        assert_eq!(subscribe(&mut poll_manager), key);
+    }
+
+    #[test]
+    fn one_shot_timer() {
+        let mut poll_manager = PollManager::new().unwrap();
+        let mut fired = 0;
+        let key = poll_manager
+            .add_timer(
+                TICK,
+                None,
+                Box::new(|fired: &mut usize| {
+                    *fired += 1;
+                    *fired
+                }),
+            )
+            .unwrap();
+
+        assert_eq!(poll_keys(&mut poll_manager, &mut fired), [key]);
+        assert_eq!(fired, 1);
+
+        poll_manager.remove_timer(key).unwrap();
+        assert!(poll_manager.remove_timer(key).is_err());
+        // The key is free again.
+        let next = poll_manager
+            .add_timer(TICK, None, Box::new(|_: &mut usize| 0))
+            .unwrap();
+        assert_eq!(next, key);
+    }
+
+    #[test]
+    fn periodic_timer() {
+        let mut poll_manager = PollManager::new().unwrap();
+        let mut fired = 0;
+        let key = poll_manager
+            .add_timer(
+                Duration::ZERO,
+                Some(TICK),
+                Box::new(|fired: &mut usize| {
+                    *fired += 1;
+                    *fired
+                }),
+            )
+            .unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(poll_keys(&mut poll_manager, &mut fired), [key]);
+        }
+        assert_eq!(fired, 3);
+    }
+
+    #[test]
+    fn signals() {
+        let mut poll_manager = PollManager::new().unwrap();
+        let key = poll_manager
+            .add_signals(&[Signal::SIGUSR1], |received: &mut usize, signal| {
+                assert_eq!(signal.unwrap(), Signal::SIGUSR1);
+                *received += 1;
+                *received
+            })
+            .unwrap();
+
+        // The signal is blocked on this thread, so raising it leaves it pending for the signalfd.
+        nix::sys::signal::raise(Signal::SIGUSR1).unwrap();
+        let mut received = 0;
+        assert_eq!(poll_keys(&mut poll_manager, &mut received), [key]);
+        assert_eq!(received, 1);
+
+        poll_manager.remove_signals(key).unwrap();
+        assert!(poll_manager.remove_signals(key).is_err());
+    }
+}
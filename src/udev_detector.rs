@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use anyhow::{Context, Result as Anyhow};
 use udev::{Device, Enumerator, MonitorBuilder, MonitorSocket};
 
@@ -8,28 +10,42 @@ use crate::{
 
 pub struct JoyconUdevDetector;
 
-const JOYCOMBINERD_TAG: &str = "joycombinerd";
-
 impl JoyconUdevDetector {
-    pub fn enumerate() -> Anyhow<Vec<Device>> {
+    pub fn enumerate(tag: &str) -> Anyhow<Vec<Device>> {
         let mut enumerator =
             Enumerator::new().with_context(|| "Failed to create a udev enumerator")?;
         enumerator
-            .match_tag(JOYCOMBINERD_TAG)
+            .match_tag(tag)
             .with_context(|| "Failed to add a tag filter to the udev enumerator")?;
         let devices = enumerator.scan_devices()?.collect();
         Ok(devices)
     }
 
-    pub fn monitor() -> Anyhow<MonitorSocket> {
+    pub fn monitor(tag: &str) -> Anyhow<MonitorSocket> {
         MonitorBuilder::new()
             .with_context(|| "Failed to create a udev monitor")?
-            .match_tag(JOYCOMBINERD_TAG)
+            .match_tag(tag)
             .with_context(|| "Failed to add a tag filter to the udev monitor")?
             .listen()
             .with_context(|| "Failed to listen to the udev monitor")
     }
 
+    /// Whether the device is the IMU of a controller.
+    pub fn is_motion_device(device: &Device) -> bool {
+        device
+            .property_value("ID_INPUT_ACCELEROMETER")
+            .is_some_and(|value| value == "1")
+    }
+
+    /// Get the syspath of the HID device an input device belongs to. The button device and the
+    /// IMU device of a controller share the same HID device.
+    pub fn hid_syspath(device: &Device) -> Anyhow<PathBuf> {
+        device
+            .parent_with_subsystem("hid")?
+            .map(|hid| hid.syspath().to_path_buf())
+            .ok_or_else(|| anyhow::anyhow!("Cannot get HID parent of {:?}", device.devpath()))
+    }
+
     fn process_monitor(monitor: &mut MonitorSocket) -> Anyhow<ControllerMessage> {
         monitor
             .iter()